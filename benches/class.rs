@@ -0,0 +1,31 @@
+// Times matching against classes with more and more disjoint ranges. The class state's ranges
+// are binary-searched, so the time per char should grow with the log of the class size rather
+// than linearly.
+//
+//   cargo bench --bench class
+
+use std::hint::black_box;
+use std::time::Instant;
+
+fn main() {
+    // Odd chars from U+0101, which none of the classes contain, so every search scans the whole
+    // input and the time is all stepping.
+    let input: String = (0..100_000)
+        .map(|i| char::from_u32(0x101 + 2 * ((i * 7919) % 0x2000)).unwrap())
+        .collect();
+    for size in [1, 16, 256, 4096] {
+        // Every other char from U+0100, so no two items merge into one range.
+        let class: String = (0..size)
+            .map(|i| char::from_u32(0x100 + 2 * i).unwrap())
+            .collect();
+        let nfa = agrep::compile(&format!("[{}]", class)).unwrap();
+        let start = Instant::now();
+        assert!(!black_box(&nfa).is_match(black_box(&input)));
+        let elapsed = start.elapsed();
+        println!(
+            "{:>5} ranges: {:>6.1} ns/char",
+            size,
+            elapsed.as_nanos() as f64 / input.chars().count() as f64
+        );
+    }
+}
@@ -1,6 +1,8 @@
 #![allow(dead_code)]
-use anyhow::{bail, Context, Result};
-// use thiserror::Error;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use thiserror::Error;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum AST {
@@ -13,15 +15,50 @@ pub enum AST {
         items: Vec<ClassItem>,
     },
     Group(Box<AST>),
-    Repetition(RepetitionType, Box<AST>),
+    // '(?flags)' applies `Flags` to the rest of the enclosing group.
+    Flags(Flags),
+    // '(?flags:...)' applies `Flags` to a non-capturing group.
+    FlagGroup(Flags, Box<AST>),
+    Lookaround(LookaroundType, Box<AST>),
+    // The flag is set for a lazy quantifier, like 'a*?'.
+    Repetition(RepetitionType, bool, Box<AST>),
     Concat(Vec<AST>),
     Alternation(Vec<AST>),
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum AnchorType {
-    LineStart, // '^'
-    LineEnd,   // '$'
+    LineStart,       // '^'
+    LineEnd,         // '$'
+    WordBoundary,    // '\b'
+    NotWordBoundary, // '\B'
+    WordStart,       // '\<'
+    WordEnd,         // '\>'
+    // Hold where the char before, or after, isn't a word char, including at the ends of the
+    // input. Whole-word mode surrounds the pattern with them, and they have no syntax.
+    NoWordBefore,
+    NoWordAfter,
+    TextStart, // '\A'
+    TextEnd,   // '\z'
+    // '\Z' holds at the end of the input or just before a final '\n', even in multiline mode.
+    TextEndBeforeNewline,
+    // '\K' always holds, but moves the start of the match to where it's passed.
+    KeepOut,
+}
+
+// Inline flags: `Some(true)` turns a flag on, `Some(false)` turns it off after a '-', and `None`
+// leaves it as it was.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Flags {
+    pub case_insensitive: Option<bool>,     // 'i'
+    pub dot_matches_new_line: Option<bool>, // 's'
+    pub swap_greed: Option<bool>,           // 'U'
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LookaroundType {
+    Lookbehind,         // '(?<=...)'
+    NegativeLookbehind, // '(?<!...)'
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -67,6 +104,54 @@ impl NamedClass {
             _ => None,
         }
     }
+
+    // Ranges matched by the class. With the `unicode-classes` feature, classes that have a
+    // Unicode counterpart cover all of it.
+    pub fn ranges(&self) -> &'static [(char, char)] {
+        #[cfg(feature = "unicode-classes")]
+        if let Some(ranges) = crate::unicode::named_class_ranges(self) {
+            return ranges;
+        }
+        self.ascii_ranges()
+    }
+
+    // Ranges in the POSIX locale.
+    fn ascii_ranges(&self) -> &'static [(char, char)] {
+        match self {
+            Self::Alnum => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+            Self::Alpha => &[('A', 'Z'), ('a', 'z')],
+            Self::Blank => &[('\t', '\t'), (' ', ' ')],
+            Self::Cntrl => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+            Self::Digit => DIGIT,
+            Self::Graph => &[('!', '~')],
+            Self::Lower => &[('a', 'z')],
+            Self::Print => &[(' ', '~')],
+            Self::Punct => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+            Self::Space => SPACE,
+            Self::Upper => &[('A', 'Z')],
+            Self::XDigit => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        }
+    }
+}
+
+impl ClassItem {
+    pub fn contains(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Ordinary(literal) | ClassItem::Equivalence(literal) => c == literal,
+            ClassItem::Range { start, end } => (start..=end).contains(&c),
+            ClassItem::Character(ref class) => class
+                .ranges()
+                .iter()
+                .any(|&(start, end)| (start..=end).contains(&c)),
+            ClassItem::Collating => false,
+        }
+    }
+}
+
+// Whether the class '[items]', or '[^items]' if `negated`, matches `c`, straight from its items
+// without building an automaton.
+pub fn class_contains(negated: bool, items: &[ClassItem], c: char) -> bool {
+    items.iter().any(|item| item.contains(c)) != negated
 }
 
 // }
@@ -81,37 +166,562 @@ pub enum RepetitionType {
     Range(u32, u32), // '{m,n}'
 }
 
+// Ranges behind the '\d', '\w' and '\s' shorthand classes.
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+// The chars '\w' matches and the word assertions like '\b' look for.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum WordChars {
+    // '[0-9A-Za-z_]'.
+    #[default]
+    Ascii,
+    // Letters and digits of every script, and '_'.
+    Unicode,
+    Custom(fn(char) -> bool),
+}
+
+impl WordChars {
+    pub fn contains(&self, c: char) -> bool {
+        match self {
+            Self::Ascii => WORD.iter().any(|&(start, end)| start <= c && c <= end),
+            Self::Unicode => is_unicode_word(c),
+            Self::Custom(is_word) => is_word(c),
+        }
+    }
+
+    // The sorted, disjoint ranges of word chars. A custom set is found by testing every char.
+    pub fn ranges(&self) -> Cow<'static, [(char, char)]> {
+        static UNICODE: OnceLock<Vec<(char, char)>> = OnceLock::new();
+        match self {
+            Self::Ascii => Cow::Borrowed(WORD),
+            Self::Unicode => Cow::Borrowed(UNICODE.get_or_init(|| ranges_of(is_unicode_word))),
+            Self::Custom(is_word) => Cow::Owned(ranges_of(*is_word)),
+        }
+    }
+}
+
+fn is_unicode_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// The sorted, disjoint ranges of chars with `property`.
+pub(crate) fn ranges_of(property: fn(char) -> bool) -> Vec<(char, char)> {
+    let mut ranges: Vec<(char, char)> = vec![];
+    for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+        if !property(c) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if *end as u32 + 1 == c as u32 => *end = c,
+            _ => ranges.push((c, c)),
+        }
+    }
+    ranges
+}
+
+type Ranges = Cow<'static, [(char, char)]>;
+
+// Maps 'd', 'w', 's' and their uppercase negations to the ranges they cover.
+fn shorthand_class(c: char, word: &WordChars) -> Option<(bool, Ranges)> {
+    let ranges = match c.to_ascii_lowercase() {
+        'd' => Cow::Borrowed(DIGIT),
+        'w' => word.ranges(),
+        's' => Cow::Borrowed(SPACE),
+        _ => return None,
+    };
+    Some((c.is_ascii_uppercase(), ranges))
+}
+
+// The complement of sorted, disjoint ranges over all chars.
+pub(crate) fn complement(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut result = vec![];
+    let mut next = Some('\0');
+    for &(start, end) in ranges {
+        if let Some(from) = next {
+            if from < start {
+                result.push((from, char_before(start)));
+            }
+        }
+        next = char_after(end);
+    }
+    if let Some(from) = next {
+        result.push((from, char::MAX));
+    }
+    result
+}
+
+// Sorts `ranges` and merges any that overlap or touch, leaving disjoint ranges in order.
+pub(crate) fn merge_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort();
+    let mut result: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match result.last_mut() {
+            Some((_, last)) if char_after(*last).is_none_or(|next| start <= next) => {
+                *last = (*last).max(end);
+            }
+            _ => result.push((start, end)),
+        }
+    }
+    result
+}
+
+fn char_before(c: char) -> char {
+    match c {
+        '\u{E000}' => '\u{D7FF}',
+        _ => char::from_u32(c as u32 - 1).unwrap(),
+    }
+}
+
+fn char_after(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        char::MAX => None,
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+pub(crate) fn ranges_to_items(ranges: &[(char, char)]) -> Vec<ClassItem> {
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                ClassItem::Ordinary(start)
+            } else {
+                ClassItem::Range { start, end }
+            }
+        })
+        .collect()
+}
+
+// Renders `ast` as a tree with one node per line, children indented under their parent:
+//
+//   Concat
+//     Literal 'a'
+//     Repetition ZeroOrMore
+//       Group
+pub fn pretty(ast: &AST) -> String {
+    let mut out = String::new();
+    pretty_node(ast, 0, &mut out);
+    out
+}
+
+fn pretty_node(ast: &AST, depth: usize, out: &mut String) {
+    let line = |out: &mut String, depth: usize, text: String| {
+        out.push_str(&"  ".repeat(depth));
+        out.push_str(&text);
+        out.push('\n');
+    };
+    let children: &[AST] = match ast {
+        AST::Empty => {
+            line(out, depth, "Empty".into());
+            &[]
+        }
+        AST::Literal(c) => {
+            line(out, depth, format!("Literal {:?}", c));
+            &[]
+        }
+        AST::Wildcard => {
+            line(out, depth, "Wildcard".into());
+            &[]
+        }
+        AST::Anchor(anchor_type) => {
+            line(out, depth, format!("Anchor {:?}", anchor_type));
+            &[]
+        }
+        AST::Class { negated, items } => {
+            line(
+                out,
+                depth,
+                if *negated { "Class ^" } else { "Class" }.into(),
+            );
+            for item in items {
+                let text = match item {
+                    ClassItem::Ordinary(c) => format!("{:?}", c),
+                    ClassItem::Range { start, end } => format!("{:?}-{:?}", start, end),
+                    ClassItem::Character(class) => format!("{:?}", class),
+                    item => format!("{:?}", item),
+                };
+                line(out, depth + 1, text);
+            }
+            &[]
+        }
+        AST::Flags(flags) => {
+            line(out, depth, format!("Flags {}", pretty_flags(flags)));
+            &[]
+        }
+        AST::Group(ast) => {
+            line(out, depth, "Group".into());
+            std::slice::from_ref(&**ast)
+        }
+        AST::FlagGroup(flags, ast) => {
+            line(out, depth, format!("FlagGroup {}", pretty_flags(flags)));
+            std::slice::from_ref(&**ast)
+        }
+        AST::Lookaround(look, ast) => {
+            line(out, depth, format!("Lookaround {:?}", look));
+            std::slice::from_ref(&**ast)
+        }
+        AST::Repetition(rep, lazy, ast) => {
+            let lazy = if *lazy { " lazy" } else { "" };
+            line(out, depth, format!("Repetition {:?}{}", rep, lazy));
+            std::slice::from_ref(&**ast)
+        }
+        AST::Concat(asts) => {
+            line(out, depth, "Concat".into());
+            asts
+        }
+        AST::Alternation(asts) => {
+            line(out, depth, "Alternation".into());
+            asts
+        }
+    };
+    for child in children {
+        pretty_node(child, depth + 1, out);
+    }
+}
+
+// Flags as they'd be written inline, like '(?i-s)'.
+fn pretty_flags(flags: &Flags) -> String {
+    let (mut on, mut off) = (String::new(), String::new());
+    for (flag, value) in [
+        ('i', flags.case_insensitive),
+        ('s', flags.dot_matches_new_line),
+        ('U', flags.swap_greed),
+    ] {
+        match value {
+            Some(true) => on.push(flag),
+            Some(false) => off.push(flag),
+            None => {}
+        }
+    }
+    if !off.is_empty() {
+        on.push('-');
+        on.push_str(&off);
+    }
+    format!("(?{})", on)
+}
+
+// Pre-order traversal over an AST. Override `visit` and call `walk` to keep descending.
+pub trait Visit {
+    fn visit(&mut self, ast: &AST) {
+        walk(self, ast);
+    }
+}
+
+pub fn walk<V: Visit + ?Sized>(visitor: &mut V, ast: &AST) {
+    match ast {
+        AST::Group(ast)
+        | AST::FlagGroup(_, ast)
+        | AST::Lookaround(_, ast)
+        | AST::Repetition(_, _, ast) => visitor.visit(ast),
+        AST::Concat(asts) | AST::Alternation(asts) => {
+            for ast in asts {
+                visitor.visit(ast);
+            }
+        }
+        AST::Empty
+        | AST::Literal(_)
+        | AST::Wildcard
+        | AST::Anchor(_)
+        | AST::Class { .. }
+        | AST::Flags(_) => {}
+    }
+}
+
+impl AST {
+    // Rewrites the tree bottom-up: children are mapped before `f` sees their parent.
+    pub fn map<F: FnMut(AST) -> AST>(self, f: &mut F) -> AST {
+        let ast = match self {
+            AST::Group(ast) => AST::Group(Box::new(ast.map(f))),
+            AST::FlagGroup(flags, ast) => AST::FlagGroup(flags, Box::new(ast.map(f))),
+            AST::Lookaround(look, ast) => AST::Lookaround(look, Box::new(ast.map(f))),
+            AST::Repetition(rep, lazy, ast) => AST::Repetition(rep, lazy, Box::new(ast.map(f))),
+            AST::Concat(asts) => AST::Concat(asts.into_iter().map(|ast| ast.map(f)).collect()),
+            AST::Alternation(asts) => {
+                AST::Alternation(asts.into_iter().map(|ast| ast.map(f)).collect())
+            }
+            ast => ast,
+        };
+        f(ast)
+    }
+
+    // Accumulates over every node in pre-order.
+    pub fn fold<T, F: FnMut(T, &AST) -> T>(&self, init: T, mut f: F) -> T {
+        struct Folder<T, F> {
+            acc: Option<T>,
+            f: F,
+        }
+
+        impl<T, F: FnMut(T, &AST) -> T> Visit for Folder<T, F> {
+            fn visit(&mut self, ast: &AST) {
+                self.acc = Some((self.f)(self.acc.take().unwrap(), ast));
+                walk(self, ast);
+            }
+        }
+
+        let mut folder = Folder {
+            acc: Some(init),
+            f: &mut f,
+        };
+        folder.visit(self);
+        folder.acc.unwrap()
+    }
+
+    // How deeply groups and repetitions nest in the tree, each of which lowering recurses into.
+    pub fn nesting_depth(&self) -> usize {
+        match self {
+            AST::Group(ast)
+            | AST::FlagGroup(_, ast)
+            | AST::Lookaround(_, ast)
+            | AST::Repetition(_, _, ast) => 1 + ast.nesting_depth(),
+            AST::Concat(asts) | AST::Alternation(asts) => {
+                asts.iter().map(AST::nesting_depth).max().unwrap_or(0)
+            }
+            AST::Empty
+            | AST::Literal(_)
+            | AST::Wildcard
+            | AST::Anchor(_)
+            | AST::Class { .. }
+            | AST::Flags(_) => 0,
+        }
+    }
+
+    // The number of chars every match consumes, or `None` if it varies.
+    pub fn fixed_width(&self) -> Option<usize> {
+        match self {
+            AST::Empty | AST::Anchor(_) | AST::Lookaround(_, _) | AST::Flags(_) => Some(0),
+            AST::Literal(_) | AST::Wildcard | AST::Class { .. } => Some(1),
+            AST::Group(ast) | AST::FlagGroup(_, ast) => ast.fixed_width(),
+            AST::Repetition(rep, _, ast) => {
+                let count = match rep {
+                    RepetitionType::Exact(n) => *n,
+                    RepetitionType::Range(n, m) if n == m => *n,
+                    _ => return None,
+                };
+                Some(count as usize * ast.fixed_width()?)
+            }
+            AST::Concat(asts) => asts.iter().map(|ast| ast.fixed_width()).sum(),
+            AST::Alternation(asts) => {
+                let width = asts.first()?.fixed_width()?;
+                asts.iter()
+                    .all(|ast| ast.fixed_width() == Some(width))
+                    .then_some(width)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum GroupKind {
+    Capture,
+    Lookaround(LookaroundType),
+    Flags(Flags),
+    // A bare '(?flags)', which doesn't open a group at all.
+    SetFlags(Flags),
+    // '(?#...)', which is skipped entirely.
+    Comment,
+}
+
+// A single char of a bracket expression, written plainly or as an escape, or the items of a
+// shorthand class like '\d', which can't be a range endpoint.
+enum ClassAtom {
+    Char(char),
+    Set(Vec<ClassItem>),
+}
+
+// Lowering and NFA construction recurse once per nesting level, so bound it well below
+// anything that could overflow the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 250;
+
+// The regex dialect being parsed. They only differ in what they accept, so far in whether empty
+// subexpressions like '()' and 'a|' are allowed: POSIX leaves them undefined, so ERE and BRE
+// reject them, while PCRE matches the empty string. Only PCRE has lazy quantifiers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Syntax {
+    ERE,
+    BRE,
+    #[default]
+    PCRE,
+}
+
 pub struct Parser {
     offset: usize,
+    // Where the construct being parsed starts, which is where errors are reported.
+    start: usize,
+    max_depth: usize,
+    syntax: Syntax,
+    // Whether '\0' and '\1'-'\7' start octal escapes, like '\012'.
+    octal: bool,
+    // Whether '^' and '$' are only anchors at the start and end of a branch, and literals
+    // anywhere else.
+    positional_anchors: bool,
+    word_chars: WordChars,
     group_stack: Vec<Vec<AST>>,
-    class_stack: Vec<Vec<AST>>,
+    group_kinds: Vec<GroupKind>,
+    // Offsets of the open groups' '('.
+    group_offsets: Vec<usize>,
 }
 
 impl Parser {
     pub fn new() -> Self {
+        Self::with_max_depth(DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(max_depth: usize) -> Self {
         Self {
             offset: 0,
+            start: 0,
+            max_depth,
+            syntax: Syntax::default(),
+            octal: false,
+            positional_anchors: false,
+            word_chars: WordChars::default(),
             group_stack: Vec::new(),
-            class_stack: Vec::new(),
+            group_kinds: Vec::new(),
+            group_offsets: Vec::new(),
         }
     }
 
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    pub fn set_octal(&mut self, octal: bool) {
+        self.octal = octal;
+    }
+
+    pub fn set_positional_anchors(&mut self, positional_anchors: bool) {
+        self.positional_anchors = positional_anchors;
+    }
+
+    // Sets the chars '\w' and '\W' expand to.
+    pub fn set_word_chars(&mut self, word_chars: WordChars) {
+        self.word_chars = word_chars;
+    }
+
     pub fn parse(&mut self, pattern: &str) -> Result<AST> {
-        ParserVM::new(self, pattern).parse()
+        let mut vm = ParserVM::new(self, pattern);
+        let ast = if cfg!(feature = "recursive-descent") {
+            vm.parse_descent()
+        } else {
+            vm.parse()
+        }?;
+        if self.syntax != Syntax::PCRE {
+            // Found after parsing, so blame the whole pattern.
+            self.start = 0;
+            check_posix_empty(&ast)?;
+        }
+        Ok(ast)
+    }
+
+    // Like `parse`, but a failure also says where it happened and what parsed before it, for
+    // live feedback while a pattern is being typed.
+    pub fn parse_partial(&mut self, pattern: &str) -> core::result::Result<AST, PartialParse> {
+        let error = match self.parse(pattern) {
+            Ok(ast) => return Ok(ast),
+            Err(error) => error,
+        };
+        // The span runs up to and including the char the parser stopped at.
+        let offset = self.start;
+        let end = self.offset.min(pattern.len());
+        let len = end - offset + pattern[end..].chars().next().map_or(0, char::len_utf8);
+        let mut prefix_parser = Parser::with_max_depth(self.max_depth);
+        prefix_parser.set_syntax(self.syntax);
+        prefix_parser.set_octal(self.octal);
+        prefix_parser.set_positional_anchors(self.positional_anchors);
+        prefix_parser.set_word_chars(self.word_chars);
+        let partial = if offset == 0 {
+            AST::Empty
+        } else {
+            match prefix_parser.parse_partial(&pattern[..offset]) {
+                Ok(ast) => ast,
+                Err(partial) => partial.partial,
+            }
+        };
+        Err(PartialParse {
+            error,
+            offset,
+            len,
+            partial,
+        })
     }
 
+    // Clears everything left over from the last parse, which may have failed midway.
     fn reset(&mut self) {
         self.offset = 0;
+        self.start = 0;
+        self.group_stack.clear();
+        self.group_kinds.clear();
+        self.group_offsets.clear();
     }
 }
 
-// #[derive(Error, Debug)]
-// enum Error {
-//     #[error("Unclosed repetition range")]
-//     UnclosedRepetitionRange,
-// }
-//
-// type Result<T> = core::result::Result<T, Error>;
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Why a pattern failed to parse, by the construct at fault. Each variant carries a description
+// that includes the offset where it went wrong.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum ParseError {
+    #[error("Unexpected EOF {0}")]
+    Eof(String),
+    #[error("Invalid group: {0}")]
+    Group(String),
+    #[error("Invalid alternation: {0}")]
+    Alternation(String),
+    #[error("Invalid flags: {0}")]
+    Flags(String),
+    #[error("Invalid lookbehind: {0}")]
+    Lookbehind(String),
+    #[error("Invalid class: {0}")]
+    Class(String),
+    #[error("Invalid repetition: {0}")]
+    Repetition(String),
+    #[error("Invalid repetition range: {0}")]
+    RepetitionRange(String),
+    #[error("Invalid escape: {0}")]
+    Escape(String),
+}
+
+type Result<T> = core::result::Result<T, ParseError>;
+
+// Like `anyhow::bail!`, returning a `ParseError` of the given kind.
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)*) => {
+        return Err(ParseError::$kind(format!($($arg)*)))
+    };
+}
+
+// A failed parse: the error, the byte span of the construct at fault, and the AST of the
+// pattern before it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartialParse {
+    pub error: ParseError,
+    pub offset: usize,
+    pub len: usize,
+    pub partial: AST,
+}
+
+// Rejects the empty groups and alternation branches that POSIX leaves undefined.
+fn check_posix_empty(ast: &AST) -> Result<()> {
+    ast.fold(Ok(()), |result, ast| {
+        result?;
+        match ast {
+            AST::Group(ast) if **ast == AST::Empty => {
+                fail!(Group, "empty group '()' isn't allowed in POSIX syntax")
+            }
+            AST::Alternation(branches) if branches.contains(&AST::Empty) => {
+                fail!(Alternation, "empty branch isn't allowed in POSIX syntax")
+            }
+            _ => Ok(()),
+        }
+    })
+}
 
 struct ParserVM<'a> {
     parser: &'a mut Parser,
@@ -148,8 +758,9 @@ impl<'a> ParserVM<'a> {
     #[allow(dead_code)]
     fn next_ok(&mut self) -> Result<()> {
         if !self.next() {
-            bail!(
-                "Unexpected EOF after '{}' at offset {}",
+            fail!(
+                Eof,
+                "after '{}' at offset {}",
                 self.char(),
                 self.parser.offset
             );
@@ -174,8 +785,8 @@ impl<'a> ParserVM<'a> {
     fn parse_int(&mut self) -> Result<u32> {
         let mut num = 0;
         self.strip();
-        while !self.is_eof() && self.char().is_digit(10) {
-            num = num * 10 + self.char().to_digit(10).unwrap() as u32;
+        while !self.is_eof() && self.char().is_ascii_digit() {
+            num = num * 10 + self.char().to_digit(10).unwrap();
             self.next_strip();
         }
         // TODO: do some validation?
@@ -184,39 +795,158 @@ impl<'a> ParserVM<'a> {
 
     fn start_group(&mut self, stack: Vec<AST>) -> Result<Vec<AST>> {
         assert!(self.char() == '(');
+        if self.parser.group_stack.len() >= self.parser.max_depth {
+            fail!(
+                Group,
+                "nesting depth exceeds limit of {} at offset {}",
+                self.parser.max_depth,
+                self.parser.offset
+            );
+        }
+        let offset = self.parser.offset;
         if !self.next() {
-            panic!("Invalid group: unexpected eof after '('");
+            fail!(Group, "unclosed '(' at offset {}", offset);
+        }
+        let kind = self.parse_group_kind()?;
+        match kind {
+            GroupKind::SetFlags(flags) => {
+                let mut stack = stack;
+                stack.push(AST::Flags(flags));
+                return Ok(stack);
+            }
+            GroupKind::Comment => return Ok(stack),
+            _ => {}
         }
+        self.parser.group_offsets.push(offset);
+        self.parser.group_kinds.push(kind);
         self.parser.group_stack.push(stack);
         Ok(Vec::new())
     }
 
+    // Consumes the '?...' header that may follow '(' and returns the kind of group it opens.
+    fn parse_group_kind(&mut self) -> Result<GroupKind> {
+        let rest = &self.pattern[self.parser.offset..];
+        let (kind, len) = if rest.starts_with("?<=") {
+            (GroupKind::Lookaround(LookaroundType::Lookbehind), 3)
+        } else if rest.starts_with("?<!") {
+            (GroupKind::Lookaround(LookaroundType::NegativeLookbehind), 3)
+        } else if rest.starts_with("?#") {
+            // Comments can't nest, so the first ')' closes it.
+            let Some(end) = rest.find(')') else {
+                fail!(
+                    Group,
+                    "unclosed comment at offset {}",
+                    self.parser.offset - 1
+                );
+            };
+            (GroupKind::Comment, end + 1)
+        } else if rest.starts_with('?') {
+            self.next();
+            return self.parse_flags();
+        } else {
+            (GroupKind::Capture, 0)
+        };
+        self.parser.offset += len;
+        Ok(kind)
+    }
+
+    // Parses the flags of '(?flags)' or '(?flags:', with '-' turning the flags after it off.
+    fn parse_flags(&mut self) -> Result<GroupKind> {
+        let start = self.parser.offset;
+        let mut flags = Flags::default();
+        let mut enable = true;
+        let mut empty = true;
+        loop {
+            if self.is_eof() {
+                fail!(Flags, "unexpected eof after '(?' at offset {}", start);
+            }
+            let c = self.char();
+            self.next();
+            let flag = match c {
+                ':' | ')' if !enable && empty => fail!(
+                    Flags,
+                    "expected a flag after '-' at offset {}",
+                    self.parser.offset - 1
+                ),
+                ':' => return Ok(GroupKind::Flags(flags)),
+                ')' if start + 1 == self.parser.offset => {
+                    fail!(Flags, "empty flags at offset {}", start)
+                }
+                ')' => return Ok(GroupKind::SetFlags(flags)),
+                '-' if enable => {
+                    enable = false;
+                    empty = true;
+                    continue;
+                }
+                'i' => &mut flags.case_insensitive,
+                's' => &mut flags.dot_matches_new_line,
+                'U' => &mut flags.swap_greed,
+                _ => fail!(
+                    Flags,
+                    "unknown flag '{}' at offset {}",
+                    c,
+                    self.parser.offset - c.len_utf8()
+                ),
+            };
+            *flag = Some(enable);
+            empty = false;
+        }
+    }
+
+    fn finish_group(&self, kind: GroupKind, ast: AST) -> Result<AST> {
+        Ok(match kind {
+            GroupKind::Capture => AST::Group(Box::new(ast)),
+            GroupKind::Flags(flags) => AST::FlagGroup(flags, Box::new(ast)),
+            GroupKind::SetFlags(flags) => AST::Flags(flags),
+            GroupKind::Comment => AST::Empty,
+            GroupKind::Lookaround(look) => {
+                if ast.fixed_width().is_none() {
+                    fail!(
+                        Lookbehind,
+                        "pattern must have a fixed width, before offset {}",
+                        self.parser.offset
+                    );
+                }
+                AST::Lookaround(look, Box::new(ast))
+            }
+        })
+    }
+
     fn end_group(&mut self, mut stack: Vec<AST>) -> Result<Vec<AST>> {
         assert!(self.char() == ')');
         self.next();
+        let kind = self
+            .parser
+            .group_kinds
+            .pop()
+            .ok_or_else(|| ParseError::Group("no group on stack".into()))?;
+        self.parser.group_offsets.pop();
         let mut group = self
             .parser
             .group_stack
             .pop()
-            .context("Invalid group: no group on stack")?;
+            .ok_or_else(|| ParseError::Group("no group on stack".into()))?;
         let concat = match stack.len() {
             0 => AST::Empty,
             1 => stack.pop().unwrap(),
             _ => AST::Concat(stack),
         };
+        // A bare alternation at the end of the enclosing stack is this group's, still in progress.
+        // Closed groups are always wrapped so they can't be mistaken for one later.
         if let Some(AST::Alternation(alt)) = group.last_mut() {
             alt.push(concat);
+            let alt = group.pop().unwrap();
+            group.push(self.finish_group(kind, alt)?);
         } else {
-            group.push(AST::Group(Box::new(concat)));
+            group.push(self.finish_group(kind, concat)?);
         }
         Ok(group)
     }
 
     fn parse_alternate(&mut self, mut stack: Vec<AST>) -> Result<Vec<AST>> {
         assert!(self.char() == '|');
-        if !self.next() {
-            panic!("Invalid alternate: unexpected eof after '|'");
-        }
+        // A '|' at the end leaves an empty last branch, which `finish_parse` adds.
+        self.next();
         if self.parser.group_stack.is_empty() {
             self.parser.group_stack.push(Vec::new());
         }
@@ -236,6 +966,10 @@ impl<'a> ParserVM<'a> {
 
     fn finish_parse(&mut self, mut stack: Vec<AST>) -> Result<AST> {
         assert!(self.is_eof());
+        if let Some(&offset) = self.parser.group_offsets.last() {
+            self.parser.start = offset;
+            fail!(Group, "unclosed '(' at offset {}", offset);
+        }
         let concat = match stack.len() {
             0 => AST::Empty,
             1 => stack.pop().unwrap(),
@@ -259,20 +993,60 @@ impl<'a> ParserVM<'a> {
         }
     }
 
+    // Parses a named class like '[:alpha:]'. Collating elements and equivalence classes aren't
+    // supported yet, and a '[' that doesn't start any of them is ordinary. Classes don't nest, so
+    // there's no state to keep beyond the current item.
     fn parse_enclosed_class(&mut self) -> Result<ClassItem> {
-        todo!()
+        assert!(self.char() == '[');
+        match self.peek() {
+            Some(':') => {}
+            Some('.' | '=') => fail!(Class, "unsupported '[' at offset {}", self.parser.offset),
+            _ => {
+                if !self.next() {
+                    fail!(Class, "unexpected eof at offset {}", self.parser.offset);
+                }
+                return Ok(ClassItem::Ordinary('['));
+            }
+        }
+        let rest = &self.pattern[self.parser.offset + 2..];
+        let Some(len) = rest.find(":]") else {
+            fail!(Class, "unclosed '[:' at offset {}", self.parser.offset);
+        };
+        let name = &rest[..len];
+        let Some(class) = NamedClass::from_str(name) else {
+            fail!(
+                Class,
+                "unknown class name '{}' at offset {}",
+                name,
+                self.parser.offset
+            );
+        };
+        // Skip to the closing ']' and then past it.
+        self.parser.offset += len + 3;
+        if !self.next() {
+            fail!(Class, "unexpected eof at offset {}", self.parser.offset);
+        }
+        Ok(ClassItem::Character(class))
     }
 
     fn parse_class(&mut self) -> Result<AST> {
         assert!(self.char() == '[');
         if !self.next() {
-            bail!("Invalid class: unexpected eof after '['");
+            fail!(
+                Class,
+                "unexpected eof after '[' at offset {}",
+                self.parser.offset
+            );
         }
 
         let mut items = vec![];
         let negated = if self.char() == '^' {
             if !self.next() {
-                bail!("Invalid class: unexpected eof after '[^'");
+                fail!(
+                    Class,
+                    "unexpected eof after '[^' at offset {}",
+                    self.parser.offset
+                );
             }
             true
         } else {
@@ -281,9 +1055,15 @@ impl<'a> ParserVM<'a> {
 
         // Note: ']' and '-' are ordinary characters if they are the first (or after negation).
         if self.char() == ']' || self.char() == '-' {
-            items.push(ClassItem::Ordinary(self.char()));
+            let c = self.char();
+            items.push(ClassItem::Ordinary(c));
             if !self.next() {
-                bail!("Invalid class: unexpected eof after '{}'", self.char());
+                fail!(
+                    Class,
+                    "unexpected eof after '{}' at offset {}",
+                    c,
+                    self.parser.offset
+                );
             }
         }
 
@@ -296,25 +1076,41 @@ impl<'a> ParserVM<'a> {
                     items.push(item);
                 }
                 _ => {
-                    if let Some('-') = self.peek() {
-                        let start = self.char();
+                    let atom_offset = self.parser.offset;
+                    let atom = self.parse_class_atom()?;
+                    // A '-' right before the closing ']' is literal rather than a range.
+                    let mut rest = self.pattern[self.parser.offset..].chars().skip(1);
+                    if rest.next() == Some('-') && rest.next() != Some(']') {
+                        let start = self.range_endpoint(atom, atom_offset)?;
                         if !self.next() || !self.next() {
-                            bail!("Invalid class: unexpected eof after '{}-'", start);
+                            fail!(
+                                Class,
+                                "unexpected eof after '{}-' at offset {}",
+                                start,
+                                self.parser.offset
+                            );
                         }
-                        let end = self.char();
+                        let end_offset = self.parser.offset;
+                        let end = self.parse_class_atom()?;
+                        let end = self.range_endpoint(end, end_offset)?;
                         if start >= end {
-                            bail!(
-                                "Invalid class: start '{}' greater than or equal to end '{}'",
+                            fail!(
+                                Class,
+                                "start '{}' greater than or equal to end '{}' at offset {}",
                                 start,
-                                end
+                                end,
+                                self.parser.offset
                             );
                         }
                         items.push(ClassItem::Range { start, end });
                     } else {
-                        items.push(ClassItem::Ordinary(self.char()));
+                        match atom {
+                            ClassAtom::Char(c) => items.push(ClassItem::Ordinary(c)),
+                            ClassAtom::Set(set) => items.extend(set),
+                        }
                     }
                     if !self.next() {
-                        bail!("Invalid class: unexpected eof");
+                        fail!(Class, "unexpected eof at offset {}", self.parser.offset);
                     }
                 }
             }
@@ -327,55 +1123,64 @@ impl<'a> ParserVM<'a> {
         assert!(
             self.char() == '?' || self.char() == '*' || self.char() == '+' || self.char() == '}'
         );
+        let offset = self.parser.offset;
         self.next();
         let ast = stack
             .pop()
-            .context("Invalid repetition: no AST on concat stack")?;
-        if let AST::Empty = ast {
-            bail!("Invalid repetition: empty AST on concat stack");
+            .ok_or_else(|| ParseError::Repetition("no AST on concat stack".into()))?;
+        if let AST::Empty | AST::Flags(_) = ast {
+            fail!(Repetition, "empty AST on concat stack");
+        }
+        // A quantifier adds a level of nesting just as a group does, as in 'a???'.
+        if ast.nesting_depth() >= self.parser.max_depth {
+            fail!(
+                Repetition,
+                "nesting depth exceeds limit of {} at offset {}",
+                self.parser.max_depth,
+                offset
+            );
         }
 
-        stack.push(AST::Repetition(rep, Box::new(ast)));
+        // POSIX has no lazy quantifiers, so there 'a*?' repeats 'a*' instead.
+        let lazy = self.parser.syntax == Syntax::PCRE && !self.is_eof() && self.char() == '?';
+        if lazy {
+            self.next();
+        }
+        stack.push(AST::Repetition(rep, lazy, Box::new(ast)));
         Ok(stack)
     }
 
     fn parse_repetition_range(&mut self) -> Result<RepetitionType> {
         assert!(self.char() == '{');
         if !self.next_strip() {
-            bail!("Invalid repetition range: unexpected eof after '{{'");
+            fail!(RepetitionRange, "unexpected eof after '{{'");
         }
 
-        let first = self
-            .parse_int()
-            .context("Invalid repetition range: no count found")?;
+        let first = self.parse_int()?;
         if self.is_eof() {
-            bail!(
-                "Invalid repetition range: unexpected eof after '{{{}'",
-                first
-            );
+            fail!(RepetitionRange, "unexpected eof after '{{{}'", first);
         }
         Ok(match self.char() {
             ',' => {
                 if !self.next_strip() {
-                    bail!(
-                        "Invalid repetition range: unexpected eof after '{{{},'",
-                        first
-                    );
+                    fail!(RepetitionRange, "unexpected eof after '{{{},'", first);
                 }
                 if self.char() == '}' {
                     RepetitionType::Lower(first)
                 } else {
                     let second = self.parse_int()?;
                     if first > second {
-                        bail!(
-                            "Invalid repetition range: first count '{}' is greater than second count '{}'",
+                        fail!(
+                            RepetitionRange,
+                            "first count '{}' is greater than second count '{}'",
                             first,
                             second
                         );
                     }
                     if self.is_eof() || self.char() != '}' {
-                        bail!(
-                            "Invalid repetition range: unexpected eof/char after '{{{},{}'",
+                        fail!(
+                            RepetitionRange,
+                            "unexpected eof/char after '{{{},{}'",
                             first,
                             second
                         );
@@ -384,58 +1189,401 @@ impl<'a> ParserVM<'a> {
                 }
             }
             '}' => RepetitionType::Exact(first),
-            _ => bail!(
-                "Invalid repetition range: expected ',' or '}}' but found '{}'",
+            _ => fail!(
+                RepetitionRange,
+                "expected ',' or '}}' but found '{}'",
                 self.char()
             ),
         })
     }
 
-    fn parse_primitive(&mut self) -> Result<AST> {
-        let prim = match self.char() {
-            '\\' => todo!(), // TODO: escape sequences
-            '.' => AST::Wildcard,
-            '^' => AST::Anchor(AnchorType::LineStart),
-            '$' => AST::Anchor(AnchorType::LineEnd),
-            _ => AST::Literal(self.char()),
-        };
-        self.next();
-        Ok(prim)
-    }
-
-    fn parse(&mut self) -> Result<AST> {
-        self.parser.reset();
-        let mut stack = vec![];
-        while !self.is_eof() {
-            match self.char() {
-                '(' => stack = self.start_group(stack)?,
-                ')' => stack = self.end_group(stack)?,
-                '|' => stack = self.parse_alternate(stack)?,
-                '[' => stack.push(self.parse_class()?),
-                '?' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrOne)?,
-                '*' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrMore)?,
-                '+' => stack = self.parse_repetition(stack, RepetitionType::OneOrMore)?,
-                '{' => {
-                    let rep = self.parse_repetition_range()?;
-                    stack = self.parse_repetition(stack, rep)?;
-                }
-                _ => stack.push(self.parse_primitive()?),
-            }
+    fn parse_escape(&mut self) -> Result<AST> {
+        assert!(self.char() == '\\');
+        if !self.next() {
+            fail!(
+                Escape,
+                "unexpected eof after '\\' at offset {}",
+                self.parser.offset
+            );
         }
-        self.finish_parse(stack)
+        let c = self.char();
+        if let Some((negated, ranges)) = shorthand_class(c, &self.parser.word_chars) {
+            return Ok(AST::Class {
+                negated,
+                items: ranges_to_items(&ranges),
+            });
+        }
+        Ok(match c {
+            'b' => AST::Anchor(AnchorType::WordBoundary),
+            'B' => AST::Anchor(AnchorType::NotWordBoundary),
+            '<' => AST::Anchor(AnchorType::WordStart),
+            '>' => AST::Anchor(AnchorType::WordEnd),
+            'K' => AST::Anchor(AnchorType::KeepOut),
+            'A' => AST::Anchor(AnchorType::TextStart),
+            'z' => AST::Anchor(AnchorType::TextEnd),
+            'Z' => AST::Anchor(AnchorType::TextEndBeforeNewline),
+            _ => AST::Literal(self.escaped_literal(c)?),
+        })
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_literal() -> Result<()> {
-        let mut parser = Parser::new();
-        let ast = parser.parse("a")?;
-        assert_eq!(ast, AST::Literal('a'));
-        Ok(())
+    fn escaped_literal(&mut self, c: char) -> Result<char> {
+        Ok(match c {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'c' => self.parse_control()?,
+            'x' => self.parse_hex()?,
+            '0'..='7' if self.parser.octal => self.parse_octal()?,
+            _ if c.is_ascii_punctuation() => c,
+            _ => fail!(
+                Escape,
+                "unknown escape '\\{}' at offset {}",
+                c,
+                self.parser.offset
+            ),
+        })
+    }
+
+    // '\cX' is the control char for 'X', i.e. 'X' with bit 6 flipped, so '\cM' is '\r' and '\c['
+    // is ESC. Lowercase letters are treated as uppercase.
+    fn parse_control(&mut self) -> Result<char> {
+        assert!(self.char() == 'c');
+        if !self.next() {
+            fail!(
+                Escape,
+                "unexpected eof after '\\c' at offset {}",
+                self.parser.offset
+            );
+        }
+        let c = self.char().to_ascii_uppercase();
+        if !('?'..='_').contains(&c) {
+            fail!(
+                Escape,
+                "invalid control char '\\c{}' at offset {}",
+                self.char(),
+                self.parser.offset
+            );
+        }
+        Ok((c as u8 ^ 0x40).into())
+    }
+
+    // '\xHH' with exactly two hex digits, or '\x{H...}' with any number of them.
+    fn parse_hex(&mut self) -> Result<char> {
+        assert!(self.char() == 'x');
+        let start = self.parser.offset - 1;
+        let braced = self.peek() == Some('{');
+        if braced {
+            self.next();
+        }
+        let mut digits = String::new();
+        while self.next() {
+            match self.char() {
+                '}' if braced => break,
+                c if c.is_ascii_hexdigit() => digits.push(c),
+                _ => break,
+            }
+            if !braced && digits.len() == 2 {
+                break;
+            }
+        }
+        let closed = !braced || (!self.is_eof() && self.char() == '}');
+        let valid = match braced {
+            true => (1..=6).contains(&digits.len()),
+            false => digits.len() == 2,
+        };
+        if !closed || !valid {
+            fail!(Escape, "invalid hex escape at offset {}", start);
+        }
+        let code = u32::from_str_radix(&digits, 16).unwrap();
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => fail!(
+                Escape,
+                "invalid code point U+{:X} at offset {}",
+                code,
+                start
+            ),
+        }
+    }
+
+    // Up to three octal digits, like '\0' or '\177'. Only enabled by `set_octal`.
+    fn parse_octal(&mut self) -> Result<char> {
+        let mut code = self.char().to_digit(8).unwrap();
+        for _ in 0..2 {
+            match self.peek().and_then(|c| c.to_digit(8)) {
+                Some(digit) => {
+                    self.next();
+                    code = code * 8 + digit;
+                }
+                None => break,
+            }
+        }
+        Ok(char::from_u32(code).unwrap())
+    }
+
+    // A plain char or an escape inside a bracket expression, leaving the cursor on its last char.
+    fn parse_class_atom(&mut self) -> Result<ClassAtom> {
+        match self.char() {
+            '\\' => self.parse_class_escape(),
+            c => Ok(ClassAtom::Char(c)),
+        }
+    }
+
+    fn range_endpoint(&self, atom: ClassAtom, offset: usize) -> Result<char> {
+        match atom {
+            ClassAtom::Char(c) => Ok(c),
+            ClassAtom::Set(_) => fail!(
+                Class,
+                "shorthand class can't be a range endpoint at offset {}",
+                offset
+            ),
+        }
+    }
+
+    // An escape inside a bracket expression. Shorthand classes are expanded into their ranges,
+    // or the complement of them for the negated forms, so they can be unioned with other items.
+    fn parse_class_escape(&mut self) -> Result<ClassAtom> {
+        assert!(self.char() == '\\');
+        if !self.next() {
+            fail!(
+                Class,
+                "unexpected eof after '\\' at offset {}",
+                self.parser.offset
+            );
+        }
+        let c = self.char();
+        Ok(match shorthand_class(c, &self.parser.word_chars) {
+            Some((false, ranges)) => ClassAtom::Set(ranges_to_items(&ranges)),
+            Some((true, ranges)) => ClassAtom::Set(ranges_to_items(&complement(&ranges))),
+            // A word boundary means nothing in a class, so there '\b' is backspace.
+            None if c == 'b' => ClassAtom::Char('\x08'),
+            None => ClassAtom::Char(self.escaped_literal(c)?),
+        })
+    }
+
+    // `\Q...\E` quotes everything up to `\E`, or the end of the pattern, as literals.
+    fn parse_quoted(&mut self, stack: &mut Vec<AST>) {
+        assert!(self.char() == '\\' && self.peek() == Some('Q'));
+        self.next();
+        self.next();
+        while !self.is_eof() {
+            if self.char() == '\\' && self.peek() == Some('E') {
+                self.next();
+                self.next();
+                break;
+            }
+            stack.push(AST::Literal(self.char()));
+            self.next();
+        }
+    }
+
+    // `branch` holds what's been parsed of the current branch so far, which decides whether '^'
+    // is at its start when anchors are positional.
+    fn parse_primitive(&mut self, branch: &[AST]) -> Result<AST> {
+        let positional = self.parser.positional_anchors;
+        let prim = match self.char() {
+            '\\' => self.parse_escape()?,
+            '.' => AST::Wildcard,
+            '^' if positional && !branch.iter().all(|ast| matches!(ast, AST::Flags(_))) => {
+                AST::Literal('^')
+            }
+            '$' if positional && !matches!(self.peek(), None | Some('|' | ')')) => {
+                AST::Literal('$')
+            }
+            '^' => AST::Anchor(AnchorType::LineStart),
+            '$' => AST::Anchor(AnchorType::LineEnd),
+            _ => AST::Literal(self.char()),
+        };
+        self.next();
+        Ok(prim)
+    }
+
+    fn parse(&mut self) -> Result<AST> {
+        self.parser.reset();
+        let mut stack = vec![];
+        while !self.is_eof() {
+            self.parser.start = self.parser.offset;
+            match self.char() {
+                '(' => stack = self.start_group(stack)?,
+                ')' => stack = self.end_group(stack)?,
+                '|' => stack = self.parse_alternate(stack)?,
+                '[' => stack.push(self.parse_class()?),
+                '?' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrOne)?,
+                '*' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrMore)?,
+                '+' => stack = self.parse_repetition(stack, RepetitionType::OneOrMore)?,
+                '{' => {
+                    let rep = self.parse_repetition_range()?;
+                    stack = self.parse_repetition(stack, rep)?;
+                }
+                '\\' if self.peek() == Some('Q') => self.parse_quoted(&mut stack),
+                _ => {
+                    let prim = self.parse_primitive(&stack)?;
+                    stack.push(prim);
+                }
+            }
+        }
+        self.finish_parse(stack)
+    }
+}
+
+// Recursive-descent alternative to the stack-based `parse`. It shares the primitive, class and
+// repetition parsers above and only replaces the group/alternation bookkeeping:
+//
+//   alternation := concat ('|' concat)*
+//   concat      := (atom repetition*)*
+//   atom        := '(' alternation ')' | class | primitive
+impl<'a> ParserVM<'a> {
+    fn parse_descent(&mut self) -> Result<AST> {
+        self.parser.reset();
+        let ast = self.parse_descent_alternation(0)?;
+        if !self.is_eof() {
+            self.parser.start = self.parser.offset;
+            fail!(Group, "unmatched ')' at offset {}", self.parser.offset);
+        }
+        Ok(ast)
+    }
+
+    fn parse_descent_alternation(&mut self, depth: usize) -> Result<AST> {
+        let mut branches = vec![self.parse_descent_concat(depth)?];
+        while !self.is_eof() && self.char() == '|' {
+            self.next();
+            branches.push(self.parse_descent_concat(depth)?);
+        }
+        Ok(match branches.len() {
+            1 => branches.pop().unwrap(),
+            _ => AST::Alternation(branches),
+        })
+    }
+
+    fn parse_descent_concat(&mut self, depth: usize) -> Result<AST> {
+        let mut stack = vec![];
+        while !self.is_eof() {
+            self.parser.start = self.parser.offset;
+            match self.char() {
+                '|' | ')' => break,
+                '(' => stack.extend(self.parse_descent_group(depth)?),
+                '[' => stack.push(self.parse_class()?),
+                '?' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrOne)?,
+                '*' => stack = self.parse_repetition(stack, RepetitionType::ZeroOrMore)?,
+                '+' => stack = self.parse_repetition(stack, RepetitionType::OneOrMore)?,
+                '{' => {
+                    let rep = self.parse_repetition_range()?;
+                    stack = self.parse_repetition(stack, rep)?;
+                }
+                '\\' if self.peek() == Some('Q') => self.parse_quoted(&mut stack),
+                _ => {
+                    let prim = self.parse_primitive(&stack)?;
+                    stack.push(prim);
+                }
+            }
+        }
+        Ok(match stack.len() {
+            0 => AST::Empty,
+            1 => stack.pop().unwrap(),
+            _ => AST::Concat(stack),
+        })
+    }
+
+    // `None` for a comment, which leaves nothing behind.
+    fn parse_descent_group(&mut self, depth: usize) -> Result<Option<AST>> {
+        assert!(self.char() == '(');
+        if depth >= self.parser.max_depth {
+            fail!(
+                Group,
+                "nesting depth exceeds limit of {} at offset {}",
+                self.parser.max_depth,
+                self.parser.offset
+            );
+        }
+        let offset = self.parser.offset;
+        self.next();
+        let kind = self.parse_group_kind()?;
+        match kind {
+            GroupKind::SetFlags(flags) => return Ok(Some(AST::Flags(flags))),
+            GroupKind::Comment => return Ok(None),
+            _ => {}
+        }
+        let ast = self.parse_descent_alternation(depth + 1)?;
+        if self.is_eof() {
+            self.parser.start = offset;
+            fail!(Group, "unclosed '(' at offset {}", offset);
+        }
+        self.next();
+        self.finish_group(kind, ast).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_empty() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse("")?, AST::Empty);
+        Ok(())
+    }
+
+    #[test]
+    fn test_syntax_empty_subexpressions() -> Result<()> {
+        let mut pcre = Parser::new();
+        let mut ere = Parser::new();
+        ere.set_syntax(Syntax::ERE);
+        assert_eq!(
+            pcre.parse("a|")?,
+            AST::Alternation(vec![AST::Literal('a'), AST::Empty])
+        );
+        assert_eq!(
+            pcre.parse("(|a)")?,
+            AST::Group(Box::new(AST::Alternation(vec![
+                AST::Empty,
+                AST::Literal('a')
+            ])))
+        );
+        assert_eq!(pcre.parse("()")?, AST::Group(Box::new(AST::Empty)));
+        assert!(matches!(ere.parse("a|"), Err(ParseError::Alternation(_))));
+        assert!(matches!(ere.parse("(|a)"), Err(ParseError::Alternation(_))));
+        assert!(matches!(ere.parse("b()"), Err(ParseError::Group(_))));
+        assert!(matches!(ere.parse("(a|())"), Err(ParseError::Group(_))));
+        assert_eq!(ere.parse("")?, AST::Empty);
+        assert_eq!(ere.parse("(a|b)")?, pcre.parse("(a|b)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_alternatives() -> Result<()> {
+        let mut parser = Parser::new();
+        let group = |branches| AST::Group(Box::new(AST::Alternation(branches)));
+        assert_eq!(
+            parser.parse("(|a|)")?,
+            group(vec![AST::Empty, AST::Literal('a'), AST::Empty])
+        );
+        assert_eq!(
+            parser.parse("(a|)")?,
+            group(vec![AST::Literal('a'), AST::Empty])
+        );
+        assert_eq!(
+            parser.parse("(|a)")?,
+            group(vec![AST::Empty, AST::Literal('a')])
+        );
+        assert_eq!(
+            parser.parse("x(||)y")?,
+            AST::Concat(vec![
+                AST::Literal('x'),
+                group(vec![AST::Empty, AST::Empty, AST::Empty]),
+                AST::Literal('y')
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal() -> Result<()> {
+        let mut parser = Parser::new();
+        let ast = parser.parse("a")?;
+        assert_eq!(ast, AST::Literal('a'));
+        Ok(())
     }
 
     #[test]
@@ -452,7 +1600,11 @@ mod tests {
         let ast = parser.parse("a+")?;
         assert_eq!(
             ast,
-            AST::Repetition(RepetitionType::OneOrMore, Box::new(AST::Literal('a')))
+            AST::Repetition(
+                RepetitionType::OneOrMore,
+                false,
+                Box::new(AST::Literal('a'))
+            )
         );
         Ok(())
     }
@@ -464,7 +1616,7 @@ mod tests {
         assert_eq!(
             ast,
             AST::Concat(vec![
-                AST::Repetition(RepetitionType::Lower(1), Box::new(AST::Literal('a'))),
+                AST::Repetition(RepetitionType::Lower(1), false, Box::new(AST::Literal('a'))),
                 AST::Literal('b')
             ])
         );
@@ -481,7 +1633,11 @@ mod tests {
                 AST::Literal('l'),
                 AST::Literal('o'),
                 AST::Literal('t'),
-                AST::Repetition(RepetitionType::Range(4, 8), Box::new(AST::Literal('s'))),
+                AST::Repetition(
+                    RepetitionType::Range(4, 8),
+                    false,
+                    Box::new(AST::Literal('s'))
+                ),
                 AST::Literal('o'),
                 AST::Literal('f'),
                 AST::Literal(' '),
@@ -492,6 +1648,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lazy_rep() -> Result<()> {
+        let mut parser = Parser::new();
+        let lazy = |rep| AST::Repetition(rep, true, Box::new(AST::Literal('a')));
+        assert_eq!(parser.parse("a+?")?, lazy(RepetitionType::OneOrMore));
+        assert_eq!(parser.parse("a??")?, lazy(RepetitionType::ZeroOrOne));
+        assert_eq!(parser.parse("a{2,}?")?, lazy(RepetitionType::Lower(2)));
+        // A second '?' after the lazy one repeats it again.
+        assert_eq!(
+            parser.parse("a*??")?,
+            AST::Repetition(
+                RepetitionType::ZeroOrOne,
+                false,
+                Box::new(lazy(RepetitionType::ZeroOrMore))
+            )
+        );
+
+        parser.set_syntax(Syntax::ERE);
+        assert_eq!(
+            parser.parse("a*?")?,
+            AST::Repetition(
+                RepetitionType::ZeroOrOne,
+                false,
+                Box::new(AST::Repetition(
+                    RepetitionType::ZeroOrMore,
+                    false,
+                    Box::new(AST::Literal('a'))
+                ))
+            )
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_recursive_rep() -> Result<()> {
         let mut parser = Parser::new();
@@ -500,8 +1689,10 @@ mod tests {
             ast,
             AST::Repetition(
                 RepetitionType::ZeroOrMore,
+                false,
                 Box::new(AST::Repetition(
                     RepetitionType::Exact(3),
+                    false,
                     Box::new(AST::Literal('a'))
                 ))
             )
@@ -544,6 +1735,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_class_trailing_hyphen() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("[a-]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('a'), ClassItem::Ordinary('-')]
+            }
+        );
+        assert_eq!(
+            parser.parse("[a-c-]")?,
+            AST::Class {
+                negated: false,
+                items: vec![
+                    ClassItem::Range {
+                        start: 'a',
+                        end: 'c'
+                    },
+                    ClassItem::Ordinary('-')
+                ]
+            }
+        );
+        let nfa = crate::compile("[a-]")?;
+        assert!(nfa.is_full_match("a"));
+        assert!(nfa.is_full_match("-"));
+        assert!(!nfa.is_match("b"));
+        assert!(!nfa.is_match("]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_anchor_chars() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("[$^]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('$'), ClassItem::Ordinary('^')]
+            }
+        );
+        assert_eq!(
+            parser.parse("[^^$]")?,
+            AST::Class {
+                negated: true,
+                items: vec![ClassItem::Ordinary('^'), ClassItem::Ordinary('$')]
+            }
+        );
+        let nfa = crate::compile("a[$^]b")?;
+        assert!(nfa.is_match("a$b"));
+        assert!(nfa.is_match("a^b"));
+        assert!(!nfa.is_match("ab"));
+        // Inside brackets neither one asserts a position.
+        let nfa = crate::compile("[$^]")?;
+        assert!(!nfa.is_match("abc"));
+        assert!(!nfa.is_match(""));
+        Ok(())
+    }
+
     #[test]
     fn test_neg_class() -> Result<()> {
         let mut parser = Parser::new();
@@ -578,4 +1828,758 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_visit_count_literals() -> Result<()> {
+        struct LiteralCounter(usize);
+
+        impl Visit for LiteralCounter {
+            fn visit(&mut self, ast: &AST) {
+                if let AST::Literal(_) = ast {
+                    self.0 += 1;
+                }
+                walk(self, ast);
+            }
+        }
+
+        let mut parser = Parser::new();
+        let ast = parser.parse("ab(c|d)*[xyz]e")?;
+        let mut counter = LiteralCounter(0);
+        counter.visit(&ast);
+        assert_eq!(counter.0, 5);
+        assert_eq!(
+            ast.fold(0, |n, ast| n + matches!(ast, AST::Literal(_)) as usize),
+            5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_rewrite() -> Result<()> {
+        let mut parser = Parser::new();
+        let ast = parser.parse("a(b)")?.map(&mut |ast| match ast {
+            AST::Group(ast) => *ast,
+            AST::Literal(c) => AST::Literal(c.to_ascii_uppercase()),
+            ast => ast,
+        });
+        assert_eq!(ast, AST::Concat(vec![AST::Literal('A'), AST::Literal('B')]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth() -> Result<()> {
+        let nested = |depth| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+
+        let mut parser = Parser::with_max_depth(3);
+        assert!(parser.parse(&nested(3)).is_ok());
+        assert!(parser.parse(&nested(4)).is_err());
+
+        let mut parser = Parser::new();
+        assert!(parser.parse(&nested(DEFAULT_MAX_DEPTH)).is_ok());
+        let err = parser.parse(&nested(100_000)).unwrap_err();
+        assert!(err.to_string().contains("nesting depth"));
+
+        // Stacked quantifiers count towards the same limit.
+        let stacked = |depth| format!("a{}", "?".repeat(depth));
+        let mut parser = Parser::new();
+        assert!(parser.parse(&stacked(DEFAULT_MAX_DEPTH)).is_ok());
+        let err = parser.parse(&stacked(50_000)).unwrap_err();
+        assert!(err.to_string().contains("nesting depth"));
+        let mut parser = Parser::with_max_depth(3);
+        assert!(parser.parse("(a*)+").is_ok());
+        assert!(parser.parse("((a*)+)?").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_descent_cross_check() -> Result<()> {
+        let corpus = [
+            "",
+            "a",
+            "abc",
+            "a+",
+            "a{1,}b",
+            "lots{   4 ,  8      }of ms",
+            "a{3}*",
+            "[abc]",
+            "[^a-z0-9 ]",
+            "[]a]",
+            "[-a]",
+            "^foo$",
+            "a.c",
+            "a|b",
+            "ab|cd|ef",
+            "(a)",
+            "(ab)c",
+            "()",
+            "(a|b)",
+            "x(a|b)y",
+            "foo(baz|bar)",
+            "(a|)",
+            "(|a)",
+            "((a))",
+            "(a(b(c)))",
+            "(ab)+",
+            "(a|b)*c",
+            "(a|b)(c|d)",
+            "(a|b)|c",
+            "a|(b|c)",
+            "a|b(c)",
+            "a{1,2}(foo|bar)[ac-z]*",
+            "foo(baz).*(bar|baz)?",
+            "ü(ñ|é)+",
+            "(?<=\\$)\\d+",
+            "a(?<!b|c)d",
+        ];
+        for pattern in corpus {
+            let mut parser = Parser::new();
+            let stack = ParserVM::new(&mut parser, pattern).parse()?;
+            let descent = ParserVM::new(&mut parser, pattern).parse_descent()?;
+            assert_eq!(stack, descent, "pattern {:?}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_error_offsets() {
+        let error = |pattern| Parser::new().parse(pattern).unwrap_err().to_string();
+        assert_eq!(
+            error("[a-"),
+            "Invalid class: unexpected eof after 'a-' at offset 3"
+        );
+        // '[:bad:]' on its own is a bracket of the chars ':bad', so the name is only checked
+        // inside another bracket.
+        assert!(Parser::new().parse("[:bad:]").is_ok());
+        assert_eq!(
+            error("x[[:bad:]]"),
+            "Invalid class: unknown class name 'bad' at offset 2"
+        );
+        assert!(error("[[:alpha").ends_with("unclosed '[:' at offset 1"));
+        assert!(error("[z-a]").ends_with("at offset 3"));
+        assert!(error("ab[c").ends_with("at offset 4"));
+        assert!(error("[^").ends_with("at offset 2"));
+        assert!(error("[]").ends_with("after ']' at offset 2"));
+        assert!(error("[\\").ends_with("at offset 2"));
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let mut parser = Parser::new();
+        let err = parser.parse_partial("a(b").unwrap_err();
+        // The span runs from the unclosed '(' to the end of its group.
+        assert_eq!((err.offset, err.len), (1, 2));
+        assert_eq!(err.partial, AST::Literal('a'));
+        assert!(matches!(err.error, ParseError::Group(_)));
+
+        let err = Parser::new().parse_partial("ab{3,1}").unwrap_err();
+        assert_eq!((err.offset, err.len), (2, 5));
+        assert_eq!(
+            err.partial,
+            AST::Concat(vec![AST::Literal('a'), AST::Literal('b')])
+        );
+        // Errors inside an unclosed group fall back to what parsed before the group.
+        let err = Parser::new().parse_partial("x(y\\q").unwrap_err();
+        assert_eq!((err.offset, err.len), (3, 2));
+        assert_eq!(err.partial, AST::Literal('x'));
+        let err = Parser::new().parse_partial("a(").unwrap_err();
+        assert_eq!((err.offset, err.len), (1, 1));
+        let err = Parser::new().parse_partial("ab)").unwrap_err();
+        assert_eq!((err.offset, err.len), (2, 1));
+        assert_eq!(
+            Parser::new().parse_partial("a(b)"),
+            Parser::new().parse("a(b)").map_err(|_| unreachable!())
+        );
+
+        let mut parser = Parser::new();
+        parser.set_syntax(Syntax::ERE);
+        let err = parser.parse_partial("ab|").unwrap_err();
+        assert_eq!((err.offset, err.len, err.partial), (0, 3, AST::Empty));
+    }
+
+    #[test]
+    fn test_descent_errors() {
+        for pattern in ["(a", "a)", "(a|b", "*a", "a{2,1}"] {
+            let mut parser = Parser::new();
+            assert!(
+                ParserVM::new(&mut parser, pattern).parse_descent().is_err(),
+                "pattern {:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_group_alt() -> Result<()> {
+        let mut parser = Parser::new();
+        let ast = parser.parse("(a|b)(c|d)")?;
+        let group = |a, b| {
+            AST::Group(Box::new(AST::Alternation(vec![
+                AST::Literal(a),
+                AST::Literal(b),
+            ])))
+        };
+        assert_eq!(ast, AST::Concat(vec![group('a', 'b'), group('c', 'd')]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("\\$\\.\\n")?,
+            AST::Concat(vec![
+                AST::Literal('$'),
+                AST::Literal('.'),
+                AST::Literal('\n')
+            ])
+        );
+        assert_eq!(
+            parser.parse("\\D")?,
+            AST::Class {
+                negated: true,
+                items: vec![ClassItem::Range {
+                    start: '0',
+                    end: '9'
+                }]
+            }
+        );
+        assert_eq!(
+            parser.parse("\\b\\B")?,
+            AST::Concat(vec![
+                AST::Anchor(AnchorType::WordBoundary),
+                AST::Anchor(AnchorType::NotWordBoundary)
+            ])
+        );
+        assert_eq!(
+            parser.parse("\\<\\>[\\<]")?,
+            AST::Concat(vec![
+                AST::Anchor(AnchorType::WordStart),
+                AST::Anchor(AnchorType::WordEnd),
+                AST::Class {
+                    negated: false,
+                    items: vec![ClassItem::Ordinary('<')]
+                }
+            ])
+        );
+        assert_eq!(
+            parser.parse("a\\K")?,
+            AST::Concat(vec![AST::Literal('a'), AST::Anchor(AnchorType::KeepOut)])
+        );
+        assert_eq!(
+            parser.parse("\\A\\z\\Z")?,
+            AST::Concat(vec![
+                AST::Anchor(AnchorType::TextStart),
+                AST::Anchor(AnchorType::TextEnd),
+                AST::Anchor(AnchorType::TextEndBeforeNewline)
+            ])
+        );
+        assert!(parser.parse("\\q").is_err());
+        assert!(parser.parse("a\\").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_positional_anchors() -> Result<()> {
+        let mut parser = Parser::new();
+        let literals = |s: &str| AST::Concat(s.chars().map(AST::Literal).collect());
+        assert_eq!(
+            parser.parse("a^b")?,
+            AST::Concat(vec![
+                AST::Literal('a'),
+                AST::Anchor(AnchorType::LineStart),
+                AST::Literal('b')
+            ])
+        );
+        parser.set_positional_anchors(true);
+        assert_eq!(parser.parse("a^b")?, literals("a^b"));
+        assert_eq!(parser.parse("a$b")?, literals("a$b"));
+        assert_eq!(
+            parser.parse("^a$")?,
+            AST::Concat(vec![
+                AST::Anchor(AnchorType::LineStart),
+                AST::Literal('a'),
+                AST::Anchor(AnchorType::LineEnd)
+            ])
+        );
+        // Each branch and group has its own start and end.
+        assert_eq!(
+            parser.parse("x|^y")?,
+            AST::Alternation(vec![
+                AST::Literal('x'),
+                AST::Concat(vec![AST::Anchor(AnchorType::LineStart), AST::Literal('y')])
+            ])
+        );
+        assert_eq!(
+            parser.parse("(a$)b^")?,
+            AST::Concat(vec![
+                AST::Group(Box::new(AST::Concat(vec![
+                    AST::Literal('a'),
+                    AST::Anchor(AnchorType::LineEnd)
+                ]))),
+                AST::Literal('b'),
+                AST::Literal('^')
+            ])
+        );
+        assert_eq!(
+            parser.parse("(?i)^a")?,
+            AST::Concat(vec![
+                AST::Flags(Flags {
+                    case_insensitive: Some(true),
+                    ..Flags::default()
+                }),
+                AST::Anchor(AnchorType::LineStart),
+                AST::Literal('a')
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("a(?#the letter a)b")?,
+            AST::Concat(vec![AST::Literal('a'), AST::Literal('b')])
+        );
+        assert_eq!(parser.parse("(?#only a comment)")?, AST::Empty);
+        assert_eq!(parser.parse("(?#x|y()a")?, AST::Literal('a'));
+        assert_eq!(
+            parser.parse("(a(?#x))")?,
+            AST::Group(Box::new(AST::Literal('a')))
+        );
+        assert!(parser.parse("a(?#unclosed").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex_escape() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse("\\x41")?, AST::Literal('A'));
+        assert_eq!(parser.parse("\\x{263A}")?, AST::Literal('☺'));
+        assert_eq!(parser.parse("\\x{1F600}")?, AST::Literal('😀'));
+        assert_eq!(
+            parser.parse("\\x412")?,
+            AST::Concat(vec![AST::Literal('A'), AST::Literal('2')])
+        );
+        assert_eq!(
+            parser.parse("[\\x41z]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('A'), ClassItem::Ordinary('z')]
+            }
+        );
+        for pattern in [
+            "\\x4",
+            "\\x4g",
+            "\\x{}",
+            "\\x{41",
+            "\\x{D800}",
+            "\\x{110000}",
+        ] {
+            assert!(parser.parse(pattern).is_err(), "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_escape_range() -> Result<()> {
+        let mut parser = Parser::new();
+        let range = |start, end| AST::Class {
+            negated: false,
+            items: vec![ClassItem::Range { start, end }],
+        };
+        assert_eq!(parser.parse("[\\x41-\\x43]")?, range('A', 'C'));
+        assert_eq!(parser.parse("[\\x{263A}-\\x{263C}]")?, range('☺', '☼'));
+        assert_eq!(parser.parse("[!-\\x7E]")?, range('!', '~'));
+        assert_eq!(parser.parse("[\\t-\\r]")?, range('\t', '\r'));
+        let nfa = crate::compile("[\\x41-\\x43]")?;
+        assert!(nfa.is_full_match("B"));
+        assert!(!nfa.is_match("-"));
+        let nfa = crate::compile("[!-\\x7E]")?;
+        assert!(nfa.is_full_match("a"));
+        assert!(!nfa.is_match("\x7F"));
+
+        // A shorthand class stands for many chars, so it can't bound a range.
+        let error = |pattern| Parser::new().parse(pattern).unwrap_err().to_string();
+        assert_eq!(
+            error("[\\d-z]"),
+            "Invalid class: shorthand class can't be a range endpoint at offset 1"
+        );
+        assert!(error("[a-\\w]").ends_with("endpoint at offset 3"));
+        assert!(error("[\\x43-\\x41]").ends_with("at offset 9"));
+        assert!(parser.parse("[\\d-]").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_octal_escape() -> Result<()> {
+        let mut parser = Parser::new();
+        assert!(parser.parse("\\0").is_err());
+        parser.set_octal(true);
+        assert_eq!(parser.parse("\\0")?, AST::Literal('\0'));
+        assert_eq!(parser.parse("\\012")?, AST::Literal('\n'));
+        assert_eq!(parser.parse("\\101")?, AST::Literal('A'));
+        assert_eq!(
+            parser.parse("\\1018")?,
+            AST::Concat(vec![AST::Literal('A'), AST::Literal('8')])
+        );
+        assert!(parser.parse("\\8").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pretty() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            pretty(&parser.parse("a(b|c)*")?),
+            "\
+Concat
+  Literal 'a'
+  Repetition ZeroOrMore
+    Group
+      Alternation
+        Literal 'b'
+        Literal 'c'
+"
+        );
+        assert_eq!(
+            pretty(&parser.parse("(?i-s:[^a-c.])x{2,3}$")?),
+            "\
+Concat
+  FlagGroup (?i-s)
+    Class ^
+      'a'-'c'
+      '.'
+  Repetition Range(2, 3)
+    Literal 'x'
+  Anchor LineEnd
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_backspace() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("[\\b]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('\x08')]
+            }
+        );
+        assert_eq!(
+            parser.parse("[a\\b]\\b")?,
+            AST::Concat(vec![
+                AST::Class {
+                    negated: false,
+                    items: vec![ClassItem::Ordinary('a'), ClassItem::Ordinary('\x08')]
+                },
+                AST::Anchor(AnchorType::WordBoundary)
+            ])
+        );
+        let nfa = crate::compile("[\\b]")?;
+        assert!(nfa.is_full_match("\x08"));
+        assert!(!nfa.is_match("b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_escape() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse("\\cM")?, AST::Literal('\r'));
+        assert_eq!(parser.parse("\\c[")?, AST::Literal('\x1B'));
+        assert_eq!(parser.parse("\\ca")?, AST::Literal('\x01'));
+        assert_eq!(parser.parse("\\c?")?, AST::Literal('\x7F'));
+        assert_eq!(
+            parser.parse("[\\cA\\cZ]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('\x01'), ClassItem::Ordinary('\x1A')]
+            }
+        );
+        assert!(parser.parse("\\c").is_err());
+        assert!(parser.parse("\\c1").is_err());
+        assert!(parser.parse("\\cé").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted() -> Result<()> {
+        let mut parser = Parser::new();
+        let literals = |s: &str| AST::Concat(s.chars().map(AST::Literal).collect());
+        assert_eq!(parser.parse("\\Qa.b*\\E")?, literals("a.b*"));
+        assert_eq!(parser.parse("\\Q(a|\\E+")?, {
+            let mut ast = literals("(a");
+            if let AST::Concat(ref mut items) = ast {
+                items.push(AST::Repetition(
+                    RepetitionType::OneOrMore,
+                    false,
+                    Box::new(AST::Literal('|')),
+                ));
+            }
+            ast
+        });
+        // Without `\E` the quote runs to the end of the pattern.
+        assert_eq!(parser.parse("x\\Q[a\\")?, literals("x[a\\"));
+        assert_eq!(parser.parse("\\Q\\E")?, AST::Empty);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flags() -> Result<()> {
+        let mut parser = Parser::new();
+        let flags = |i, s| Flags {
+            case_insensitive: i,
+            dot_matches_new_line: s,
+            swap_greed: None,
+        };
+        assert_eq!(
+            parser.parse("(?i)a")?,
+            AST::Concat(vec![AST::Flags(flags(Some(true), None)), AST::Literal('a')])
+        );
+        assert_eq!(
+            parser.parse("(?s-i:a)")?,
+            AST::FlagGroup(flags(Some(false), Some(true)), Box::new(AST::Literal('a')))
+        );
+        assert_eq!(
+            parser.parse("(?:a|b)")?,
+            AST::FlagGroup(
+                flags(None, None),
+                Box::new(AST::Alternation(vec![AST::Literal('a'), AST::Literal('b')]))
+            )
+        );
+        for pattern in ["(?)", "(?-)", "(?i-:a)", "(?x)", "(?i", "(?i)*", "(?i--s)"] {
+            assert!(parser.parse(pattern).is_err(), "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookbehind() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("(?<=a)b")?,
+            AST::Concat(vec![
+                AST::Lookaround(LookaroundType::Lookbehind, Box::new(AST::Literal('a'))),
+                AST::Literal('b')
+            ])
+        );
+        assert_eq!(
+            parser.parse("(?<!ab|cd)")?,
+            AST::Lookaround(
+                LookaroundType::NegativeLookbehind,
+                Box::new(AST::Alternation(vec![
+                    AST::Concat(vec![AST::Literal('a'), AST::Literal('b')]),
+                    AST::Concat(vec![AST::Literal('c'), AST::Literal('d')])
+                ]))
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookbehind_variable_width() {
+        for pattern in ["(?<=a+)b", "(?<=a|bc)d", "(?<=a{1,2})"] {
+            let err = Parser::new().parse(pattern).unwrap_err();
+            assert!(err.to_string().contains("fixed width"), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_class_shorthand() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("[\\d.]")?,
+            AST::Class {
+                negated: false,
+                items: vec![
+                    ClassItem::Range {
+                        start: '0',
+                        end: '9'
+                    },
+                    ClassItem::Ordinary('.')
+                ]
+            }
+        );
+        assert_eq!(
+            parser.parse("[\\D]")?,
+            AST::Class {
+                negated: false,
+                items: vec![
+                    ClassItem::Range {
+                        start: '\0',
+                        end: '/'
+                    },
+                    ClassItem::Range {
+                        start: ':',
+                        end: char::MAX
+                    }
+                ]
+            }
+        );
+        assert_eq!(
+            parser.parse("[\\]]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary(']')]
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_after_error() -> Result<()> {
+        let mut parser = Parser::new();
+        // Fails inside the group, leaving it open.
+        assert!(parser.parse("(a(b[").is_err());
+        assert_eq!(
+            parser.parse("x|y")?,
+            AST::Alternation(vec![AST::Literal('x'), AST::Literal('y')])
+        );
+        assert!(parser.parse("(?i:a\\q").is_err());
+        assert_eq!(
+            parser.parse("(c)")?,
+            AST::Group(Box::new(AST::Literal('c')))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_contains() -> anyhow::Result<()> {
+        let mut parser = Parser::new();
+        let samples = ['a', 'm', 'z', 'A', '0', '5', '9', '/', ' ', '\n', 'é', '∂'];
+        for (pattern, expected) in [
+            ("[a-z]", "amz"),
+            ("[^0-9]", "amzA/ \né∂"),
+            ("[[:digit:]]", "059"),
+        ] {
+            let AST::Class { negated, items } = parser.parse(pattern)? else {
+                panic!("{} isn't a class", pattern);
+            };
+            let nfa = crate::compile(pattern)?;
+            for c in samples {
+                let contains = class_contains(negated, &items, c);
+                assert_eq!(contains, expected.contains(c), "{} {:?}", pattern, c);
+                assert_eq!(
+                    contains,
+                    nfa.is_full_match(&c.to_string()),
+                    "{} {:?}",
+                    pattern,
+                    c
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_no_nesting() -> Result<()> {
+        let mut parser = Parser::new();
+        let class = |items: &str| AST::Class {
+            negated: false,
+            items: items.chars().map(ClassItem::Ordinary).collect(),
+        };
+        assert_eq!(parser.parse("[[]")?, class("["));
+        // The first ']' closes the class, so the second is a literal.
+        assert_eq!(
+            parser.parse("[[a]]")?,
+            AST::Concat(vec![class("[a"), AST::Literal(']')])
+        );
+        assert_eq!(
+            parser.parse("[[:digit:]a]b")?,
+            AST::Concat(vec![
+                AST::Class {
+                    negated: false,
+                    items: vec![
+                        ClassItem::Character(NamedClass::Digit),
+                        ClassItem::Ordinary('a')
+                    ]
+                },
+                AST::Literal('b')
+            ])
+        );
+        assert!(parser.parse("[[:digit:]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_class() -> Result<()> {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.parse("[[:alpha:]_]")?,
+            AST::Class {
+                negated: false,
+                items: vec![
+                    ClassItem::Character(NamedClass::Alpha),
+                    ClassItem::Ordinary('_')
+                ]
+            }
+        );
+        assert_eq!(
+            parser.parse("[^[:digit:][:space:]]")?,
+            AST::Class {
+                negated: true,
+                items: vec![
+                    ClassItem::Character(NamedClass::Digit),
+                    ClassItem::Character(NamedClass::Space)
+                ]
+            }
+        );
+        assert_eq!(
+            parser.parse("[a[b]")?,
+            AST::Class {
+                negated: false,
+                items: vec![
+                    ClassItem::Ordinary('a'),
+                    ClassItem::Ordinary('['),
+                    ClassItem::Ordinary('b')
+                ]
+            }
+        );
+        assert_eq!(
+            parser.parse("[[]")?,
+            AST::Class {
+                negated: false,
+                items: vec![ClassItem::Ordinary('[')]
+            }
+        );
+        assert!(parser.parse("[a[").is_err());
+        assert!(parser.parse("[[=a=]]").is_err());
+        assert!(parser.parse("[[:foo:]]").is_err());
+        assert!(parser.parse("[[:alpha]").is_err());
+        assert!(parser.parse("[[:alpha:]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        assert_eq!(
+            merge_ranges(vec![
+                ('x', 'z'),
+                ('a', 'c'),
+                ('b', 'f'),
+                ('g', 'g'),
+                ('i', 'i')
+            ]),
+            [('a', 'g'), ('i', 'i'), ('x', 'z')]
+        );
+        assert_eq!(
+            merge_ranges(vec![('\u{E000}', '\u{E001}'), ('\0', '\u{D7FF}')]),
+            [('\0', '\u{E001}')]
+        );
+        assert_eq!(
+            merge_ranges(vec![('a', char::MAX), ('b', 'c')]),
+            [('a', char::MAX)]
+        );
+    }
+
+    #[test]
+    fn test_complement() {
+        assert_eq!(
+            complement(&[('\0', 'a'), ('\u{D000}', '\u{D7FF}')]),
+            vec![('b', '\u{CFFF}'), ('\u{E000}', char::MAX)]
+        );
+        assert_eq!(complement(&[('\0', char::MAX)]), vec![]);
+    }
 }
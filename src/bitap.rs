@@ -0,0 +1,169 @@
+use crate::regex::{self, Regex, RepetitionType};
+
+// Patterns with at most this many positions fit in the state word.
+const MAX_LEN: usize = u64::BITS as usize;
+
+// A Shift-Or matcher for patterns that are a fixed sequence of chars and classes, like
+// 'ab[0-9]c', with at most 64 of them. Bit `i` of the state is clear while the last `i + 1` chars
+// read match the first `i + 1` positions, so each char costs a shift and an or.
+#[derive(Clone, Debug)]
+pub struct BitapMatcher {
+    // The chars each position accepts, as sorted disjoint ranges.
+    positions: Vec<Vec<(char, char)>>,
+    // The masks of ASCII chars, precomputed since they're the common case.
+    ascii: [u64; 128],
+}
+
+impl BitapMatcher {
+    // Returns `None` unless every match of `regex` is the same sequence of positions.
+    pub fn new(regex: &Regex) -> Option<Self> {
+        let mut positions = vec![];
+        flatten(regex, &mut positions)?;
+        if positions.is_empty() || positions.len() > MAX_LEN {
+            return None;
+        }
+        let mut matcher = Self {
+            positions,
+            ascii: [0; 128],
+        };
+        for c in 0..128u8 {
+            matcher.ascii[c as usize] = matcher.compute_mask(c as char);
+        }
+        Some(matcher)
+    }
+
+    // The number of chars in every match.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    fn compute_mask(&self, c: char) -> u64 {
+        let mut mask = !0;
+        for (i, ranges) in self.positions.iter().enumerate() {
+            let index = ranges.partition_point(|&(_, end)| end < c);
+            if ranges.get(index).is_some_and(|&(start, _)| start <= c) {
+                mask &= !(1 << i);
+            }
+        }
+        mask
+    }
+
+    fn mask(&self, c: char) -> u64 {
+        match self.ascii.get(c as usize) {
+            Some(&mask) => mask,
+            None => self.compute_mask(c),
+        }
+    }
+
+    pub fn is_match(&self, input: &str) -> bool {
+        self.find(input).is_some()
+    }
+
+    // The byte range of the leftmost match. Every match has the same length, so the first one
+    // to end is also the first to start.
+    pub fn find(&self, input: &str) -> Option<(usize, usize)> {
+        let last = 1 << (self.len() - 1);
+        let mut state = !0u64;
+        for (i, c) in input.char_indices() {
+            state = (state << 1) | self.mask(c);
+            if state & last == 0 {
+                let end = i + c.len_utf8();
+                let start = input[..end]
+                    .char_indices()
+                    .nth_back(self.len() - 1)
+                    .unwrap()
+                    .0;
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
+
+// Appends the positions `regex` matches in order, or returns `None` if they aren't fixed.
+fn flatten(regex: &Regex, positions: &mut Vec<Vec<(char, char)>>) -> Option<()> {
+    match regex {
+        Regex::Empty => {}
+        Regex::Literal(chars) => positions.extend(chars.iter().map(|&c| vec![(c, c)])),
+        Regex::Class { negated, items } => positions.push(regex::class_ranges(*negated, items)),
+        Regex::Group(_, regex) => flatten(regex, positions)?,
+        Regex::Repetition(RepetitionType::Exact(n), _, regex) => {
+            for _ in 0..*n {
+                flatten(regex, positions)?;
+                if positions.len() > MAX_LEN {
+                    return None;
+                }
+            }
+        }
+        Regex::Concat(regexes) => {
+            for regex in regexes {
+                flatten(regex, positions)?;
+            }
+        }
+        Regex::Assert(_)
+        | Regex::Lookaround { .. }
+        | Regex::Repetition(..)
+        | Regex::Alternation(_) => return None,
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn matcher(pattern: &str) -> Result<Option<BitapMatcher>> {
+        Ok(BitapMatcher::new(&crate::parse(pattern)?))
+    }
+
+    #[test]
+    fn test_eligible() -> Result<()> {
+        for pattern in ["a", "ab[0-9]c", "(ab)x{3}", "[^a].é"] {
+            assert!(matcher(pattern)?.is_some(), "{}", pattern);
+        }
+        let long = "a".repeat(65);
+        for pattern in ["", "a+", "a|bc", "^a", "a?", "a{2,3}", &long] {
+            assert!(matcher(pattern)?.is_none(), "{}", pattern);
+        }
+        assert_eq!(matcher(&long[1..])?.unwrap().len(), 64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_agrees_with_nfa() -> Result<()> {
+        let inputs = [
+            "",
+            "a",
+            "ab1c",
+            "xxab1cab2c",
+            "aab9c",
+            "ab c",
+            "éab0cé",
+            "ab1",
+            "∂∂ab5c",
+            "xxa",
+            "xyxxb",
+        ];
+        for pattern in [
+            "ab[0-9]c",
+            "a",
+            "[a-c][^a]",
+            "(ab)?",
+            "b.c",
+            "∂a",
+            "x{2}[ab]",
+        ] {
+            let nfa = crate::compile(pattern)?;
+            let Some(bitap) = matcher(pattern)? else {
+                continue;
+            };
+            for input in inputs {
+                let expected = nfa.find_iter(input).next().map(|m| (m.start(), m.end()));
+                assert_eq!(bitap.find(input), expected, "{} on {:?}", pattern, input);
+            }
+        }
+        Ok(())
+    }
+}
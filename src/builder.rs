@@ -0,0 +1,534 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    ast,
+    nfa::NFA,
+    regex::{self, LineTerminator},
+};
+
+// Collects compilation options before building an `NFA`:
+//
+//   RegexBuilder::new().case_insensitive(true).multiline(true).build("^abc$")
+#[derive(Clone, Debug)]
+pub struct RegexBuilder {
+    case_insensitive: bool,
+    multiline: bool,
+    dot_matches_new_line: bool,
+    swap_greed: bool,
+    line_terminator: LineTerminator,
+    nest_limit: usize,
+    syntax: ast::Syntax,
+    octal: bool,
+    positional_anchors: bool,
+    size_limit: Option<usize>,
+    unbounded_cap: Option<u32>,
+    alphabet: Option<Vec<char>>,
+    grapheme: bool,
+    whole_word: bool,
+    whole_line: bool,
+    word_chars: ast::WordChars,
+}
+
+impl RegexBuilder {
+    pub fn new() -> Self {
+        Self {
+            case_insensitive: false,
+            multiline: false,
+            dot_matches_new_line: false,
+            swap_greed: false,
+            line_terminator: LineTerminator::LF,
+            nest_limit: ast::DEFAULT_MAX_DEPTH,
+            syntax: ast::Syntax::default(),
+            octal: false,
+            positional_anchors: false,
+            size_limit: None,
+            unbounded_cap: None,
+            alphabet: None,
+            grapheme: false,
+            whole_word: false,
+            whole_line: false,
+            word_chars: ast::WordChars::default(),
+        }
+    }
+
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.case_insensitive = yes;
+        self
+    }
+
+    // '^' and '$' also match at the start and end of every line. Without it they match only at
+    // the start and end of the whole input, whatever line terminators it contains.
+    pub fn multiline(&mut self, yes: bool) -> &mut Self {
+        self.multiline = yes;
+        self
+    }
+
+    // When enabled, '.' also matches the line terminator, which it doesn't by default.
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut Self {
+        self.dot_matches_new_line = yes;
+        self
+    }
+
+    // Makes quantifiers lazy by default, so 'a+' takes as few as it can and 'a+?' as many.
+    pub fn swap_greed(&mut self, yes: bool) -> &mut Self {
+        self.swap_greed = yes;
+        self
+    }
+
+    pub fn line_terminator(&mut self, line_terminator: LineTerminator) -> &mut Self {
+        self.line_terminator = line_terminator;
+        self
+    }
+
+    // Maximum nesting depth of groups.
+    pub fn nest_limit(&mut self, limit: usize) -> &mut Self {
+        self.nest_limit = limit;
+        self
+    }
+
+    pub fn syntax(&mut self, syntax: ast::Syntax) -> &mut Self {
+        self.syntax = syntax;
+        self
+    }
+
+    // Accept octal escapes like '\0' and '\012'.
+    pub fn octal(&mut self, yes: bool) -> &mut Self {
+        self.octal = yes;
+        self
+    }
+
+    // Only treat '^' and '$' as anchors at the start and end of a branch, so 'a^b' matches the
+    // text "a^b", as many tools do.
+    pub fn positional_anchors(&mut self, yes: bool) -> &mut Self {
+        self.positional_anchors = yes;
+        self
+    }
+
+    // Maximum number of states in the compiled automaton.
+    pub fn size_limit(&mut self, limit: usize) -> &mut Self {
+        self.size_limit = Some(limit);
+        self
+    }
+
+    // Rewrites unbounded repetitions like 'a*' and 'a{2,}' to stop at `cap` repeats, which keeps
+    // the automaton bounded for DFA construction and hardens it against hostile patterns.
+    pub fn unbounded_cap(&mut self, cap: Option<u32>) -> &mut Self {
+        self.unbounded_cap = cap;
+        self
+    }
+
+    // Declares every char the input can contain, like "ACGT" for DNA, so '.' and negated classes
+    // only range over those rather than all of Unicode.
+    pub fn alphabet(&mut self, alphabet: Option<&str>) -> &mut Self {
+        self.alphabet = alphabet.map(|alphabet| alphabet.chars().collect());
+        self
+    }
+
+    // Match grapheme clusters rather than chars, so '.' takes a char with its combining marks.
+    pub fn grapheme(&mut self, yes: bool) -> &mut Self {
+        self.grapheme = yes;
+        self
+    }
+
+    // Only match where the pattern is a whole word, as with `grep -w`: the chars on either side
+    // of a match can't be word chars. Unlike surrounding it with '\b', this also works for
+    // patterns that start or end with a non-word char, like '@user'.
+    pub fn whole_word(&mut self, yes: bool) -> &mut Self {
+        self.whole_word = yes;
+        self
+    }
+
+    // Only match where the pattern spans a whole line, as with `grep -x`, by surrounding it with
+    // '^' and '$'.
+    pub fn whole_line(&mut self, yes: bool) -> &mut Self {
+        self.whole_line = yes;
+        self
+    }
+
+    // What counts as a word char, both for '\w' and '\W' and for '\b' and the other word
+    // assertions.
+    pub fn word_chars(&mut self, word_chars: ast::WordChars) -> &mut Self {
+        self.word_chars = word_chars;
+        self
+    }
+
+    pub fn build(&self, pattern: &str) -> Result<NFA> {
+        let regex = self.parser().parse(&self.parse(pattern)?);
+        self.check_size(&regex)?;
+        self.finish(NFA::from_regex(&regex))
+    }
+
+    // Compiles several patterns into one automaton that matches wherever any of them does, as
+    // with `grep -e p1 -e p2`, and reports which one matched. Each pattern keeps its own inline
+    // flags, and capture groups are numbered across all of them in order.
+    pub fn build_patterns(&self, patterns: &[&str]) -> Result<NFA> {
+        let branches = patterns
+            .iter()
+            .map(|pattern| self.parse(pattern))
+            .collect::<Result<Vec<_>>>()?;
+        let regex = self.parser().parse_patterns(&branches);
+        self.check_size(&regex)?;
+        self.finish(NFA::from_pattern_alternation(&regex))
+    }
+
+    // Parses `pattern` into a group of its own, so its flags can't leak into anything around it.
+    fn parse(&self, pattern: &str) -> Result<ast::AST> {
+        let mut parser = ast::Parser::with_max_depth(self.nest_limit);
+        parser.set_syntax(self.syntax);
+        parser.set_octal(self.octal);
+        parser.set_positional_anchors(self.positional_anchors);
+        parser.set_word_chars(self.word_chars);
+        let ast = parser.parse(pattern)?;
+        let mut ast = ast::AST::FlagGroup(ast::Flags::default(), Box::new(ast));
+        let surround = |ast, start, end| {
+            ast::AST::Concat(vec![ast::AST::Anchor(start), ast, ast::AST::Anchor(end)])
+        };
+        if self.whole_word {
+            ast = surround(
+                ast,
+                ast::AnchorType::NoWordBefore,
+                ast::AnchorType::NoWordAfter,
+            );
+        }
+        if self.whole_line {
+            ast = surround(ast, ast::AnchorType::LineStart, ast::AnchorType::LineEnd);
+        }
+        Ok(ast)
+    }
+
+    fn parser(&self) -> regex::Parser {
+        let mut parser = regex::Parser::new();
+        parser.set_case_insensitive(self.case_insensitive);
+        parser.set_dot_matches_new_line(self.dot_matches_new_line);
+        parser.set_swap_greed(self.swap_greed);
+        parser.set_line_terminator(self.line_terminator);
+        parser.set_unbounded_cap(self.unbounded_cap);
+        parser.set_alphabet(self.alphabet.as_deref());
+        parser
+    }
+
+    // Bails before building anything when even the estimate is too big, as in 'a{1000}{1000}'.
+    fn check_size(&self, regex: &regex::Regex) -> Result<()> {
+        if let Some(limit) = self.size_limit {
+            if regex.min_states() > limit {
+                bail!(
+                    "Invalid pattern: compiled size of at least {} states exceeds limit of {}",
+                    regex.min_states(),
+                    limit
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self, mut nfa: NFA) -> Result<NFA> {
+        nfa.set_grapheme(self.grapheme);
+        nfa.set_word_chars(self.word_chars);
+        if self.multiline {
+            nfa.set_line_terminator(Some(self.line_terminator));
+        }
+        if let Some(limit) = self.size_limit {
+            if nfa.num_states() > limit {
+                bail!(
+                    "Invalid pattern: compiled size of {} states exceeds limit of {}",
+                    nfa.num_states(),
+                    limit
+                );
+            }
+        }
+        Ok(nfa)
+    }
+}
+
+impl Default for RegexBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let nfa = RegexBuilder::new()
+            .case_insensitive(true)
+            .multiline(true)
+            .size_limit(100)
+            .build("^a[b-c].$")?;
+        assert!(nfa.is_match("xx\nAbx\nyy"));
+        assert!(nfa.is_match("aCx"));
+        assert!(!nfa.is_match("ab\n"));
+
+        let nfa = RegexBuilder::new().build("^a[b-c].$")?;
+        assert!(!nfa.is_match("xx\nabx\nyy"));
+        assert!(!nfa.is_match("Abx"));
+        assert!(nfa.is_match("abx"));
+        assert!(!nfa.is_match("ab\n"));
+
+        let nfa = RegexBuilder::new()
+            .dot_matches_new_line(true)
+            .build("^a[b-c].$")?;
+        assert!(nfa.is_match("ab\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_limits() {
+        let err = RegexBuilder::new()
+            .size_limit(10)
+            .build("a{20}")
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds limit of 10"));
+        assert!(RegexBuilder::new().size_limit(10).build("a").is_ok());
+        // Rejected from the estimate, without building a million states first.
+        let err = RegexBuilder::new()
+            .size_limit(1000)
+            .build("a{1000}{1000}")
+            .unwrap_err();
+        assert!(err.to_string().contains("at least 1000000 states"));
+        assert!(RegexBuilder::new().nest_limit(2).build("((a))").is_ok());
+        assert!(RegexBuilder::new().nest_limit(2).build("(((a)))").is_err());
+    }
+
+    #[test]
+    fn test_inline_flags() -> Result<()> {
+        let nfa = crate::compile("(?i)a(?-i:b)c")?;
+        assert!(nfa.is_full_match("AbC"));
+        assert!(!nfa.is_full_match("ABC"));
+
+        let nfa = RegexBuilder::new()
+            .case_insensitive(true)
+            .build("a(?-i:b)c")?;
+        assert!(nfa.is_full_match("AbC"));
+        assert!(!nfa.is_full_match("ABC"));
+
+        // Flags set inside a group end with it, but carry over to later alternatives.
+        let nfa = crate::compile("((?i)a)a|b")?;
+        assert!(nfa.is_full_match("Aa"));
+        assert!(!nfa.is_full_match("AA"));
+        let nfa = crate::compile("x(?i)a|b")?;
+        assert!(nfa.is_full_match("B"));
+
+        let nfa = RegexBuilder::new()
+            .dot_matches_new_line(false)
+            .build("a.(?s:.)")?;
+        assert!(nfa.is_full_match("ab\n"));
+        assert!(!nfa.is_full_match("a\nb"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_negated_class_ignores_dotall() -> Result<()> {
+        for dotall in [false, true] {
+            let mut builder = RegexBuilder::new();
+            builder.dot_matches_new_line(dotall);
+            let negated = builder.build("[^a]")?;
+            assert!(negated.is_full_match("\n"));
+            assert!(negated.is_full_match("b"));
+            assert!(!negated.is_full_match("a"));
+            assert_eq!(builder.build(".")?.is_full_match("\n"), dotall);
+            assert!(builder.build("(?s).")?.is_full_match("\n"));
+            assert!(builder.build("(?s)[^a]")?.is_full_match("\n"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_positional_anchors() -> Result<()> {
+        let mut builder = RegexBuilder::new();
+        assert!(!builder.build("a^b")?.is_match("a^b"));
+        builder.positional_anchors(true);
+        let nfa = builder.build("a^b")?;
+        assert!(nfa.is_full_match("a^b"));
+        assert!(!nfa.is_match("ab"));
+        let nfa = builder.build("^a$b$")?;
+        assert!(nfa.is_full_match("a$b"));
+        assert!(!nfa.is_match("xa$b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_word_chars() -> Result<()> {
+        let mut builder = RegexBuilder::new();
+        assert!(!builder.build("\\w")?.is_match("é"));
+        assert!(builder.build("\\bword\\b")?.is_match("éwordé"));
+
+        builder.word_chars(ast::WordChars::Unicode);
+        assert!(builder.build("\\w")?.is_full_match("é"));
+        assert!(builder.build("[\\W]")?.is_full_match("-"));
+        assert!(!builder.build("\\W")?.is_match("éß_9"));
+        let nfa = builder.build("\\bword\\b")?;
+        assert!(!nfa.is_match("éwordé"));
+        assert!(nfa.is_match("le word."));
+        assert!(!builder.whole_word(true).build("mot")?.is_match("motné"));
+
+        let nfa = RegexBuilder::new()
+            .word_chars(ast::WordChars::Custom(|c| {
+                c.is_ascii_alphanumeric() || c == '-'
+            }))
+            .build("\\b\\w+\\b")?;
+        assert_eq!(nfa.find("(well-known)").unwrap().range(), 1..11);
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_greed() -> Result<()> {
+        let range = |nfa: &NFA, input| nfa.find(input).unwrap().range();
+        let nfa = RegexBuilder::new().build("a+")?;
+        assert_eq!(range(&nfa, "baaa"), 1..4);
+        let nfa = RegexBuilder::new().build("a+?")?;
+        assert_eq!(range(&nfa, "baaa"), 1..2);
+
+        let mut builder = RegexBuilder::new();
+        builder.swap_greed(true);
+        assert_eq!(range(&builder.build("a+")?, "baaa"), 1..2);
+        assert_eq!(range(&builder.build("a+?")?, "baaa"), 1..4);
+        assert_eq!(range(&builder.build("a{1,3}")?, "aaaa"), 0..1);
+        assert_eq!(range(&builder.build("a*b")?, "aab"), 0..3);
+        // The 'U' flag swaps it back.
+        assert_eq!(range(&builder.build("(?-U)a+")?, "baaa"), 1..4);
+        assert_eq!(range(&RegexBuilder::new().build("(?U)a+")?, "baaa"), 1..2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alphabet() -> Result<()> {
+        let mut builder = RegexBuilder::new();
+        builder.alphabet(Some("ACGT"));
+        let nfa = builder.build("[^A]")?;
+        for input in ["C", "G", "T"] {
+            assert!(nfa.is_full_match(input), "{}", input);
+        }
+        for input in ["A", "X", "\n"] {
+            assert!(!nfa.is_match(input), "{}", input);
+        }
+        let nfa = builder.build("A.G")?;
+        assert!(nfa.is_full_match("ATG"));
+        assert!(!nfa.is_full_match("AXG"));
+        let nfa = builder.case_insensitive(true).build("[^a]")?;
+        assert!(!nfa.is_match("A"));
+        assert!(nfa.is_full_match("T"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbounded_cap() -> Result<()> {
+        let nfa = RegexBuilder::new().unbounded_cap(Some(5)).build("a{2,}")?;
+        assert!(nfa.is_full_match("aaaaa"));
+        assert!(!nfa.is_full_match("aaaaaa"));
+        assert!(!nfa.is_full_match("a"));
+        assert!(RegexBuilder::new().build("a{2,}")?.is_full_match("aaaaaa"));
+        let nfa = RegexBuilder::new()
+            .unbounded_cap(Some(1))
+            .build("a{3,}b*")?;
+        assert!(nfa.is_full_match("aaab"));
+        assert!(!nfa.is_full_match("aaaab"));
+        assert!(!nfa.is_full_match("aaabb"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_syntax() -> Result<()> {
+        for pattern in ["(|a)", "a|", "()"] {
+            let nfa = RegexBuilder::new()
+                .syntax(ast::Syntax::PCRE)
+                .build(pattern)?;
+            assert_eq!(nfa.find("xyz").unwrap().range(), 0..0, "{}", pattern);
+            let err = RegexBuilder::new()
+                .syntax(ast::Syntax::ERE)
+                .build(pattern)
+                .unwrap_err();
+            assert!(err.to_string().contains("POSIX"), "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_whole_word() -> Result<()> {
+        let nfa = RegexBuilder::new().whole_word(true).build("foo|ba")?;
+        assert!(nfa.is_match("a foo b"));
+        assert!(nfa.is_match("ba"));
+        assert!(!nfa.is_match("foobar"));
+        assert!(!nfa.is_match("bar"));
+        let nfa = RegexBuilder::new()
+            .whole_word(true)
+            .build_patterns(&["cat", "dog"])?;
+        assert_eq!(nfa.find("catdog dog").unwrap().pattern(), 1);
+        assert!(!nfa.is_match("cats"));
+
+        let nfa = RegexBuilder::new().whole_word(true).build("cat")?;
+        assert_eq!(nfa.find("a cat").unwrap().range(), 2..5);
+        assert!(!nfa.is_match("category"));
+        assert!(!nfa.is_match("bobcat"));
+        // Edges that aren't word chars need no boundary, only no word char beside them.
+        let nfa = RegexBuilder::new().whole_word(true).build("@cat!")?;
+        assert!(nfa.is_match("hi @cat!"));
+        assert!(nfa.is_match("@cat!"));
+        assert!(!nfa.is_match("x@cat!"));
+        assert!(!nfa.is_match("@cat!y"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_whole_line() -> Result<()> {
+        let nfa = RegexBuilder::new().whole_line(true).build("foo|ba")?;
+        assert!(nfa.is_match("foo"));
+        assert!(nfa.is_match("ba"));
+        assert!(!nfa.is_match("foobar"));
+        assert!(!nfa.is_match("xfoo"));
+        let nfa = RegexBuilder::new()
+            .whole_line(true)
+            .multiline(true)
+            .build("b.")?;
+        assert_eq!(nfa.find_iter("ba\nbab\nbc").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_terminator() -> Result<()> {
+        let nfa = RegexBuilder::new()
+            .multiline(true)
+            .line_terminator(LineTerminator::CRLF)
+            .build("b$")?;
+        assert_eq!(nfa.find("ab\r\ncd").unwrap().range(), 1..2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_without_multiline() -> Result<()> {
+        // A buffer holding several lines, matched as one string: '^' and '$' see only its ends
+        // and '.' never crosses a line.
+        let mut builder = RegexBuilder::new();
+        let find = |builder: &RegexBuilder, pattern: &str| -> Result<Option<Range<usize>>> {
+            Ok(builder.build(pattern)?.find("ab\ncd").map(|m| m.range()))
+        };
+        assert_eq!(find(&builder, "^ab")?, Some(0..2));
+        assert_eq!(find(&builder, "^cd")?, None);
+        assert_eq!(find(&builder, "cd$")?, Some(3..5));
+        assert_eq!(find(&builder, "ab$")?, None);
+        assert_eq!(find(&builder, "^ab$")?, None);
+        assert_eq!(find(&builder, "a.*")?, Some(0..2));
+        assert_eq!(find(&builder, ".+$")?, Some(3..5));
+        assert_eq!(find(&builder, "b.c")?, None);
+        assert_eq!(find(&builder, "^.*$")?, None);
+        assert_eq!(find(&builder, "b\nc")?, Some(1..4));
+
+        // '$' doesn't hold before a trailing newline; '\Z' does.
+        assert!(builder.build("cd$")?.find("ab\ncd\n").is_none());
+        assert_eq!(
+            builder.build("cd\\Z")?.find("ab\ncd\n").unwrap().range(),
+            3..5
+        );
+        assert!(builder.build("ab\\Z")?.find("ab\ncd\n").is_none());
+
+        // With dotall on, '.' may cross, and the anchors are unchanged.
+        builder.dot_matches_new_line(true);
+        assert_eq!(find(&builder, "b.c")?, Some(1..4));
+        assert_eq!(find(&builder, "^.*$")?, Some(0..5));
+        assert_eq!(find(&builder, "^cd")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::Result;
+
+use crate::nfa::NFA;
+
+pub const DEFAULT_CAPACITY: usize = 64;
+
+// Compiled patterns keyed by their source, evicting the least recently used beyond `capacity`.
+pub struct Cache {
+    capacity: usize,
+    // Most recently used last.
+    entries: Vec<(String, Arc<NFA>)>,
+    hits: usize,
+    misses: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Vec::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Patterns that fail to compile aren't cached.
+    pub fn get_or_compile(&mut self, pattern: &str) -> Result<Arc<NFA>> {
+        if let Some(index) = self.entries.iter().position(|(p, _)| p == pattern) {
+            self.hits += 1;
+            let entry = self.entries.remove(index);
+            let nfa = entry.1.clone();
+            self.entries.push(entry);
+            return Ok(nfa);
+        }
+        self.misses += 1;
+        let nfa = Arc::new(crate::compile(pattern)?);
+        // With no capacity nothing is kept, so there's nothing to evict either.
+        if self.capacity > 0 {
+            if self.entries.len() >= self.capacity {
+                self.entries.remove(0);
+            }
+            self.entries.push((pattern.to_string(), nfa.clone()));
+        }
+        Ok(nfa)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
+// Compiles `pattern` through a process-wide cache shared between threads.
+pub fn get_or_compile(pattern: &str) -> Result<Arc<NFA>> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    let cache = CACHE.get_or_init(|| Mutex::new(Cache::new(DEFAULT_CAPACITY)));
+    // A panic while holding the lock can't leave the cache inconsistent.
+    let mut cache = cache.lock().unwrap_or_else(|err| err.into_inner());
+    cache.get_or_compile(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_hits() -> Result<()> {
+        let mut cache = Cache::new(2);
+        let first = cache.get_or_compile("a+")?;
+        let second = cache.get_or_compile("a+")?;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+        assert!(cache.get_or_compile("a{2").is_err());
+        assert_eq!(cache.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_evicts() -> Result<()> {
+        let mut cache = Cache::new(2);
+        cache.get_or_compile("a")?;
+        cache.get_or_compile("b")?;
+        // Using "a" makes "b" the least recently used.
+        cache.get_or_compile("a")?;
+        cache.get_or_compile("c")?;
+        assert_eq!(cache.len(), 2);
+        cache.get_or_compile("a")?;
+        assert_eq!(cache.hits(), 2);
+        cache.get_or_compile("b")?;
+        assert_eq!(cache.misses(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_without_capacity() -> Result<()> {
+        let mut cache = Cache::new(0);
+        let first = cache.get_or_compile("a")?;
+        let second = cache.get_or_compile("a")?;
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(cache.is_empty());
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_cache() -> Result<()> {
+        let handles: Vec<_> = (0..4)
+            .map(|_| std::thread::spawn(|| NFA::get_or_compile("shared[0-9]")))
+            .collect();
+        let nfas = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert!(nfas.iter().all(|nfa| Arc::ptr_eq(nfa, &nfas[0])));
+        assert!(nfas[0].is_match("shared7"));
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+// Simple case folding: chars are equivalent if one maps to the other through a one-to-one
+// lowercase or uppercase mapping, so 'k', 'K' and the Kelvin sign 'K' form one orbit. Mappings
+// to several chars, like 'ß' to "SS", are full folding and left out.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+// Every char with another case, mapped to the other chars in its orbit. Built on first use
+// from the standard library's case tables.
+fn orbits() -> &'static BTreeMap<char, Vec<char>> {
+    static ORBITS: OnceLock<BTreeMap<char, Vec<char>>> = OnceLock::new();
+    ORBITS.get_or_init(|| {
+        let mut parent: BTreeMap<char, char> = BTreeMap::new();
+        fn find(parent: &mut BTreeMap<char, char>, c: char) -> char {
+            let p = *parent.entry(c).or_insert(c);
+            if p == c {
+                return c;
+            }
+            let root = find(parent, p);
+            parent.insert(c, root);
+            root
+        }
+        for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+            for mapped in [single(c.to_lowercase()), single(c.to_uppercase())] {
+                if let Some(other) = mapped.filter(|&other| other != c) {
+                    let (a, b) = (find(&mut parent, c), find(&mut parent, other));
+                    parent.insert(a, b);
+                }
+            }
+        }
+
+        let mut groups: BTreeMap<char, Vec<char>> = BTreeMap::new();
+        let chars: Vec<char> = parent.keys().copied().collect();
+        for c in chars {
+            let root = find(&mut parent, c);
+            groups.entry(root).or_default().push(c);
+        }
+        let mut orbits = BTreeMap::new();
+        for group in groups.values() {
+            for &c in group {
+                let others = group.iter().copied().filter(|&o| o != c).collect();
+                orbits.insert(c, others);
+            }
+        }
+        orbits
+    })
+}
+
+fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+// The chars `c` is case-insensitively equal to, excluding itself.
+pub(crate) fn case_variants(c: char) -> &'static [char] {
+    orbits().get(&c).map_or(&[], |others| others)
+}
+
+// Chars with another case in `start..=end`, along with their variants.
+pub(crate) fn variants_in(start: char, end: char) -> impl Iterator<Item = (char, &'static [char])> {
+    orbits()
+        .range(start..=end)
+        .map(|(&c, others)| (c, others.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orbits() {
+        let mut k = case_variants('k').to_vec();
+        k.sort();
+        assert_eq!(k, ['K', '\u{212A}']);
+        assert_eq!(case_variants('\u{212A}').len(), 2);
+        let mut sigma = case_variants('σ').to_vec();
+        sigma.sort();
+        assert_eq!(sigma, ['Σ', 'ς']);
+        assert_eq!(case_variants('ß'), ['ẞ']);
+        // 'İ' only lowercases to two chars, so it has no simple folding.
+        assert!(!case_variants('i').contains(&'İ'));
+        assert!(case_variants('1').is_empty());
+    }
+}
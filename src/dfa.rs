@@ -0,0 +1,446 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::nfa::{Input, StateID, NFA};
+
+// A deterministic automaton over classes of chars. Each class is an inclusive range of code
+// points that every NFA transition treats the same way, so a DFA state needs one transition per
+// class rather than per char.
+#[derive(Debug)]
+pub struct DFA {
+    classes: Vec<(u32, u32)>,
+    // `transitions[state][class]` is the next state.
+    transitions: Vec<Vec<StateID>>,
+    accepting: Vec<bool>,
+    // `dead[state]` is set for states that can't reach an accepting state.
+    dead: Vec<bool>,
+    initial: StateID,
+}
+
+impl DFA {
+    // Subset construction. Returns `None` for automata with assertions or lookarounds, whose
+    // transitions depend on more than the current char.
+    pub fn from_nfa(nfa: &NFA) -> Option<Self> {
+        if !nfa.is_built() {
+            return None;
+        }
+        let mut boundaries = vec![];
+        for state in &nfa.states {
+            for transition in &state.transitions {
+                match transition.input {
+                    Input::Range(start, end) => {
+                        boundaries.push(start as u32);
+                        boundaries.push(end as u32 + 1);
+                    }
+                    Input::Class(ref ranges) => {
+                        for &(start, end) in ranges.iter() {
+                            boundaries.push(start as u32);
+                            boundaries.push(end as u32 + 1);
+                        }
+                    }
+                    Input::Assert(_) | Input::Lookaround(_) => return None,
+                    Input::Epsilon | Input::Save(_) | Input::Pattern(_) | Input::Branch(_) => {}
+                }
+            }
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        let classes: Vec<(u32, u32)> = boundaries
+            .windows(2)
+            .map(|bounds| (bounds[0], bounds[1] - 1))
+            .collect();
+
+        let mut dfa = Self {
+            classes,
+            transitions: Vec::new(),
+            accepting: Vec::new(),
+            dead: Vec::new(),
+            initial: 0,
+        };
+        let mut ids: HashMap<Vec<StateID>, StateID> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let initial = closure(nfa, vec![nfa.initial]);
+        dfa.add_state(nfa, &initial);
+        ids.insert(initial.clone(), 0);
+        queue.push_back(initial);
+
+        while let Some(set) = queue.pop_front() {
+            let id = ids[&set];
+            for class in 0..dfa.classes.len() {
+                let (start, _) = dfa.classes[class];
+                let next: Vec<StateID> = set
+                    .iter()
+                    .flat_map(|&state| &nfa.states[state].transitions)
+                    .filter(|transition| match transition.input {
+                        Input::Range(lo, hi) => lo as u32 <= start && start <= hi as u32,
+                        Input::Class(ref ranges) => char::from_u32(start)
+                            .is_some_and(|c| crate::nfa::ranges_contain(ranges, c)),
+                        _ => false,
+                    })
+                    .map(|transition| transition.next)
+                    .collect();
+                let next = closure(nfa, next);
+                let next_id = match ids.get(&next) {
+                    Some(&next_id) => next_id,
+                    None => {
+                        let next_id = dfa.add_state(nfa, &next);
+                        ids.insert(next.clone(), next_id);
+                        queue.push_back(next);
+                        next_id
+                    }
+                };
+                dfa.transitions[id][class] = next_id;
+            }
+        }
+        dfa.mark_dead();
+        Some(dfa)
+    }
+
+    fn add_state(&mut self, nfa: &NFA, set: &[StateID]) -> StateID {
+        self.transitions.push(vec![0; self.classes.len()]);
+        self.accepting
+            .push(set.iter().any(|&state| nfa.is_accepting(state)));
+        self.transitions.len() - 1
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    // Moore's algorithm: start from the accepting/non-accepting split and refine blocks by where
+    // their transitions lead until nothing changes.
+    pub fn minimize(&self) -> Self {
+        let mut blocks: Vec<usize> = self.accepting.iter().map(|&a| a as usize).collect();
+        loop {
+            let mut signatures: HashMap<(usize, Vec<usize>), usize> = HashMap::new();
+            let refined: Vec<usize> = (0..self.len())
+                .map(|state| {
+                    let signature = (
+                        blocks[state],
+                        self.transitions[state]
+                            .iter()
+                            .map(|&next| blocks[next])
+                            .collect(),
+                    );
+                    let count = signatures.len();
+                    *signatures.entry(signature).or_insert(count)
+                })
+                .collect();
+            // Refinement only ever splits blocks, so an unchanged count means a fixed point.
+            let done = count_blocks(&refined) == count_blocks(&blocks);
+            blocks = refined;
+            if done {
+                break;
+            }
+        }
+
+        let count = count_blocks(&blocks);
+        let mut transitions = vec![vec![0; self.classes.len()]; count];
+        let mut accepting = vec![false; count];
+        for state in 0..self.len() {
+            let block = blocks[state];
+            accepting[block] = self.accepting[state];
+            for (class, &next) in self.transitions[state].iter().enumerate() {
+                transitions[block][class] = blocks[next];
+            }
+        }
+        let mut dfa = Self {
+            classes: self.classes.clone(),
+            transitions,
+            accepting,
+            dead: Vec::new(),
+            initial: blocks[self.initial],
+        };
+        dfa.mark_dead();
+        dfa
+    }
+
+    // Walks the transitions backwards from the accepting states; whatever isn't reached is dead.
+    fn mark_dead(&mut self) {
+        let mut predecessors = vec![vec![]; self.len()];
+        for (state, transitions) in self.transitions.iter().enumerate() {
+            for &next in transitions {
+                predecessors[next].push(state);
+            }
+        }
+        let mut live = self.accepting.clone();
+        let mut stack: Vec<StateID> = (0..self.len()).filter(|&state| live[state]).collect();
+        while let Some(state) = stack.pop() {
+            for &previous in &predecessors[state] {
+                if !live[previous] {
+                    live[previous] = true;
+                    stack.push(previous);
+                }
+            }
+        }
+        self.dead = live.into_iter().map(|live| !live).collect();
+    }
+
+    // True if the whole of `input` is accepted. Gives up as soon as a dead state is reached, so
+    // input that goes wrong early is rejected without reading the rest.
+    pub fn is_full_match(&self, input: &str) -> bool {
+        self.run(input).0
+    }
+
+    // Whether `input` is accepted, and how many chars were read to decide.
+    fn run(&self, input: &str) -> (bool, usize) {
+        let mut state = self.initial;
+        for (steps, c) in input.chars().enumerate() {
+            let Some(class) = self.class_of(c as u32) else {
+                return (false, steps + 1);
+            };
+            state = self.transitions[state][class];
+            if self.dead[state] {
+                return (false, steps + 1);
+            }
+        }
+        (self.accepting[state], input.chars().count())
+    }
+
+    // True if some string is accepted by both automata. Walks the product of the two from their
+    // initial states, splitting classes wherever either automaton's boundaries fall.
+    pub fn intersects(&self, other: &DFA) -> bool {
+        let mut boundaries: Vec<u32> = self
+            .classes
+            .iter()
+            .chain(&other.classes)
+            .flat_map(|&(start, end)| [start, end + 1])
+            .collect();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut seen = HashSet::from([(self.initial, other.initial)]);
+        let mut queue = VecDeque::from([(self.initial, other.initial)]);
+        while let Some((left, right)) = queue.pop_front() {
+            if self.accepting[left] && other.accepting[right] {
+                return true;
+            }
+            for &c in &boundaries {
+                let (Some(a), Some(b)) = (self.class_of(c), other.class_of(c)) else {
+                    continue;
+                };
+                let next = (self.transitions[left][a], other.transitions[right][b]);
+                if seen.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        false
+    }
+
+    fn class_of(&self, c: u32) -> Option<usize> {
+        let index = self.classes.partition_point(|&(_, end)| end < c);
+        self.classes
+            .get(index)
+            .is_some_and(|&(start, _)| start <= c)
+            .then_some(index)
+    }
+
+    // Hashes the automaton with states numbered in breadth-first order from the initial state,
+    // runs of classes with the same target merged, and dead states left out. For a minimal
+    // DFA this only depends on the language.
+    pub fn canonical_hash(&self) -> u64 {
+        let mut order = vec![usize::MAX; self.len()];
+        let mut queue = VecDeque::from([self.initial]);
+        order[self.initial] = 0;
+        let mut count = 1;
+        let mut hasher = DefaultHasher::new();
+        while let Some(state) = queue.pop_front() {
+            self.accepting[state].hash(&mut hasher);
+            let mut runs: Vec<(u32, u32, usize)> = vec![];
+            for (class, &next) in self.transitions[state].iter().enumerate() {
+                if self.dead[next] {
+                    continue;
+                }
+                if order[next] == usize::MAX {
+                    order[next] = count;
+                    count += 1;
+                    queue.push_back(next);
+                }
+                let (start, end) = self.classes[class];
+                match runs.last_mut() {
+                    Some(run) if run.1 + 1 == start && run.2 == order[next] => run.1 = end,
+                    _ => runs.push((start, end, order[next])),
+                }
+            }
+            runs.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+// How the input fed to a `PartialMatcher` so far relates to the pattern.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    // The input so far is a full match.
+    Matched,
+    // The input so far isn't a match, but more input could make it one.
+    PossiblyMatches,
+    // No input added to what's there can make a match.
+    NoMatch,
+}
+
+// Matches input that arrives a piece at a time, such as text being typed, against the whole
+// pattern. Only the current DFA state is kept, so each piece costs a step per char rather than
+// a rerun over everything fed so far.
+#[derive(Debug)]
+pub struct PartialMatcher {
+    dfa: DFA,
+    // `None` once a char has led outside the automaton.
+    state: Option<StateID>,
+}
+
+impl PartialMatcher {
+    // Returns `None` for automata with assertions or lookarounds, as `DFA::from_nfa` does.
+    pub fn new(nfa: &NFA) -> Option<Self> {
+        let dfa = DFA::from_nfa(nfa)?.minimize();
+        let state = Some(dfa.initial);
+        Some(Self { dfa, state })
+    }
+
+    // Feeds the next piece of input and returns the status of everything fed so far.
+    pub fn push(&mut self, text: &str) -> Status {
+        for c in text.chars() {
+            let Some(state) = self.state else {
+                break;
+            };
+            self.state = self
+                .dfa
+                .class_of(c as u32)
+                .map(|class| self.dfa.transitions[state][class])
+                .filter(|&next| !self.dfa.dead[next]);
+        }
+        self.status()
+    }
+
+    pub fn status(&self) -> Status {
+        match self.state {
+            None => Status::NoMatch,
+            Some(state) if self.dfa.accepting[state] => Status::Matched,
+            Some(_) => Status::PossiblyMatches,
+        }
+    }
+
+    // Forgets the input fed so far.
+    pub fn reset(&mut self) {
+        self.state = Some(self.dfa.initial);
+    }
+}
+
+fn count_blocks(blocks: &[usize]) -> usize {
+    blocks.iter().max().map_or(0, |&max| max + 1)
+}
+
+// The sorted set of states reachable from `states` without consuming input.
+fn closure(nfa: &NFA, mut states: Vec<StateID>) -> Vec<StateID> {
+    let mut seen = vec![false; nfa.states.len()];
+    let mut stack = states.clone();
+    states.clear();
+    while let Some(state) = stack.pop() {
+        if seen[state] {
+            continue;
+        }
+        seen[state] = true;
+        states.push(state);
+        for transition in &nfa.states[state].transitions {
+            if let Input::Epsilon | Input::Save(_) | Input::Pattern(_) | Input::Branch(_) =
+                transition.input
+            {
+                stack.push(transition.next);
+            }
+        }
+    }
+    states.sort_unstable();
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    fn hash(pattern: &str) -> Result<u64> {
+        Ok(crate::compile(pattern)?.canonical_hash())
+    }
+
+    #[test]
+    fn test_equivalent_hash() -> Result<()> {
+        assert_eq!(hash("a+")?, hash("aa*")?);
+        assert_eq!(hash("[a-c]")?, hash("a|b|c")?);
+        assert_eq!(hash("(ab|ac)")?, hash("a(b|c)")?);
+        assert_eq!(hash("a{3}")?, hash("aaa")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_different_hash() -> Result<()> {
+        assert_ne!(hash("abc")?, hash("abd")?);
+        assert_ne!(hash("a+")?, hash("b+")?);
+        assert_ne!(hash("[a-c]")?, hash("[a-d]")?);
+        assert_ne!(hash("ab")?, hash("ba")?);
+        assert_ne!(hash("^a")?, hash("a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_full_match() -> Result<()> {
+        let dfa = super::DFA::from_nfa(&crate::compile("[a-z]+[0-9]")?).unwrap();
+        assert!(dfa.is_full_match("abc7"));
+        assert!(!dfa.is_full_match("abc"));
+        assert!(!dfa.is_full_match("abc7x"));
+        assert!(!dfa.is_full_match("é"));
+        // Rejected at the ':' or '-', without reading the rest.
+        for bad in [':', '-'] {
+            let input = format!("ab{}{}", bad, "c".repeat(10_000));
+            assert_eq!(dfa.run(&input), (false, 3));
+            assert_eq!(dfa.minimize().run(&input), (false, 3));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_matcher() -> Result<()> {
+        use super::{PartialMatcher, Status};
+        let mut matcher = PartialMatcher::new(&crate::compile("hello")?).unwrap();
+        assert_eq!(matcher.status(), Status::PossiblyMatches);
+        assert_eq!(matcher.push("he"), Status::PossiblyMatches);
+        assert_eq!(matcher.push("l"), Status::PossiblyMatches);
+        assert_eq!(matcher.push("l"), Status::PossiblyMatches);
+        assert_eq!(matcher.push("o"), Status::Matched);
+        assert_eq!(matcher.push("!"), Status::NoMatch);
+        assert_eq!(matcher.push("hello"), Status::NoMatch);
+        matcher.reset();
+        assert_eq!(matcher.push("hex"), Status::NoMatch);
+
+        let mut matcher = PartialMatcher::new(&crate::compile("[0-9]+(px|em)?")?).unwrap();
+        assert_eq!(matcher.push("12"), Status::Matched);
+        assert_eq!(matcher.push("p"), Status::PossiblyMatches);
+        assert_eq!(matcher.push("x"), Status::Matched);
+        assert!(PartialMatcher::new(&crate::compile("\\bhello")?).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimize() -> Result<()> {
+        let nfa = crate::compile("ab|cb")?;
+        let dfa = super::DFA::from_nfa(&nfa).unwrap();
+        // After 'a' and after 'c' collapse, as do the two accepting states.
+        assert_eq!(dfa.len(), 6);
+        assert_eq!(dfa.minimize().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_states() {
+        // 1 and 2 loop between each other without ever accepting; 3 accepts.
+        let mut dfa = super::DFA {
+            classes: vec![(0, 0), (1, 1)],
+            transitions: vec![vec![1, 3], vec![2, 2], vec![1, 1], vec![3, 3]],
+            accepting: vec![false, false, false, true],
+            dead: Vec::new(),
+            initial: 0,
+        };
+        dfa.mark_dead();
+        assert_eq!(dfa.dead, [false, true, true, false]);
+    }
+}
@@ -0,0 +1,61 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod ast;
+pub mod bitap;
+pub mod builder;
+pub mod cache;
+mod casefold;
+pub mod dfa;
+pub mod nfa;
+pub mod regex;
+#[cfg(feature = "unicode-classes")]
+mod unicode;
+
+use anyhow::Result;
+
+pub use crate::builder::RegexBuilder;
+use crate::nfa::NFA;
+
+// Checks that `pattern` is syntactically valid without lowering or compiling it.
+pub fn validate(pattern: &str) -> std::result::Result<(), ast::ParseError> {
+    ast::Parser::new().parse(pattern).map(drop)
+}
+
+pub fn parse(pattern: &str) -> Result<regex::Regex> {
+    Ok(pattern.parse()?)
+}
+
+pub fn compile(pattern: &str) -> Result<NFA> {
+    Ok(NFA::from_regex(&parse(pattern)?))
+}
+
+// Compiles `pattern` so that '.' excludes `line_terminator` and '^'/'$' match around it.
+pub fn compile_with_line_terminator(
+    pattern: &str,
+    line_terminator: regex::LineTerminator,
+) -> Result<NFA> {
+    RegexBuilder::new()
+        .multiline(true)
+        .dot_matches_new_line(false)
+        .line_terminator(line_terminator)
+        .build(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate("(a|b)+c{2,3}"), Ok(()));
+        assert_eq!(validate(""), Ok(()));
+        assert_eq!(
+            validate("a{3,2}"),
+            Err(ast::ParseError::RepetitionRange(
+                "first count '3' is greater than second count '2'".into()
+            ))
+        );
+        assert!(matches!(validate("[b-a]"), Err(ast::ParseError::Class(_))));
+        assert!(matches!(validate("\\q"), Err(ast::ParseError::Escape(_))));
+    }
+}
@@ -1,38 +1,244 @@
-mod ast;
-mod regex;
-mod nfa;
+#![allow(clippy::upper_case_acronyms)]
 
-use anyhow::Result;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-use crate::nfa::NFA;
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
 
-fn parse(pattern: &str) -> Result<regex::Regex> {
-    let mut parser = ast::Parser::new();
-    let ast = parser.parse(pattern)?;
-    let regex = regex::Parser::new().parse(&ast);
-    Ok(regex)
+use agrep::ast::Syntax;
+use agrep::nfa::NFA;
+use agrep::RegexBuilder;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Never,
+    Always,
+    Auto,
+}
+
+#[derive(Parser)]
+#[command(version, about = "Search for lines matching a regular expression")]
+struct Args {
+    #[arg(required_unless_present = "regexp")]
+    pattern: Option<String>,
+    /// Files to search, reading stdin when none are given
+    files: Vec<PathBuf>,
+    /// Search for PATTERN, which may be given several times to match any of them
+    #[arg(short = 'e', long, value_name = "PATTERN")]
+    regexp: Vec<String>,
+    /// Highlight matched text
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Print only the matched parts of a line, one per line
+    #[arg(short = 'o', long)]
+    only_matching: bool,
+    /// Use POSIX extended syntax, where empty subexpressions are errors
+    #[arg(short = 'E', long, conflicts_with = "perl_regexp")]
+    extended_regexp: bool,
+    /// Use Perl-compatible syntax (the default)
+    #[arg(short = 'P', long)]
+    perl_regexp: bool,
+    /// Only match whole words
+    #[arg(short = 'w', long)]
+    word_regexp: bool,
+    /// Only match whole lines
+    #[arg(short = 'x', long)]
+    line_regexp: bool,
+    /// Select non-matching lines
+    #[arg(short = 'v', long)]
+    invert_match: bool,
+    /// Print only a count of selected lines
+    #[arg(short = 'c', long)]
+    count: bool,
+    /// Prefix each line of output with its line number
+    #[arg(short = 'n', long)]
+    line_number: bool,
+}
+
+impl Args {
+    // With '-e' every positional argument is a file.
+    fn patterns_and_files(&self) -> (Vec<&str>, Vec<PathBuf>) {
+        if self.regexp.is_empty() {
+            let pattern = self.pattern.as_deref().unwrap_or_default();
+            return (vec![pattern], self.files.clone());
+        }
+        let files = self
+            .pattern
+            .iter()
+            .map(PathBuf::from)
+            .chain(self.files.iter().cloned())
+            .collect();
+        (self.regexp.iter().map(String::as_str).collect(), files)
+    }
 }
 
-#[allow(dead_code)]
-enum Type {
-    ERE,
-    BRE,
-    PCRE,
+const COLOR_MATCH: &str = "\x1b[1;31m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+struct Searcher<'a> {
+    nfa: &'a NFA,
+    color: bool,
+    only_matching: bool,
+    invert_match: bool,
+    count: bool,
+    line_number: bool,
+}
+
+impl<'a> Searcher<'a> {
+    fn new(nfa: &'a NFA, args: &Args) -> Self {
+        let color = match args.color {
+            ColorChoice::Never => false,
+            ColorChoice::Always => true,
+            ColorChoice::Auto => io::stdout().is_terminal(),
+        };
+        Self {
+            nfa,
+            color,
+            only_matching: args.only_matching,
+            invert_match: args.invert_match,
+            count: args.count,
+            line_number: args.line_number,
+        }
+    }
+
+    // Lines are streamed one at a time so memory stays bounded regardless of input size.
+    fn search<R: BufRead, W: Write>(
+        &self,
+        reader: R,
+        out: &mut W,
+        prefix: Option<&str>,
+    ) -> Result<bool> {
+        let mut count = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if self.nfa.is_match(&line) == self.invert_match {
+                continue;
+            }
+            count += 1;
+            if !self.count {
+                self.print(&line, i + 1, out, prefix)?;
+            }
+        }
+        if self.count {
+            if let Some(prefix) = prefix {
+                write!(out, "{}:", prefix)?;
+            }
+            writeln!(out, "{}", count)?;
+        }
+        Ok(count > 0)
+    }
+
+    fn print<W: Write>(
+        &self,
+        line: &str,
+        line_number: usize,
+        out: &mut W,
+        prefix: Option<&str>,
+    ) -> Result<()> {
+        // Inverted lines have no matches, so there's nothing to extract or highlight.
+        if self.invert_match {
+            if !self.only_matching {
+                self.write_prefix(out, prefix, line_number)?;
+                writeln!(out, "{}", line)?;
+            }
+            return Ok(());
+        }
+
+        // Empty matches have nothing to print or highlight.
+        let matches = self.nfa.find_iter(line).filter(|m| !m.is_empty());
+        if self.only_matching {
+            for m in matches {
+                self.write_prefix(out, prefix, line_number)?;
+                self.write_match(&line[m.range()], out)?;
+                writeln!(out)?;
+            }
+            return Ok(());
+        }
+
+        self.write_prefix(out, prefix, line_number)?;
+        if !self.color {
+            writeln!(out, "{}", line)?;
+            return Ok(());
+        }
+        let mut last = 0;
+        for m in matches {
+            write!(out, "{}", &line[last..m.start()])?;
+            self.write_match(&line[m.range()], out)?;
+            last = m.end();
+        }
+        writeln!(out, "{}", &line[last..])?;
+        Ok(())
+    }
+
+    // Writes the file name and line number, if enabled, before a line of output.
+    fn write_prefix<W: Write>(
+        &self,
+        out: &mut W,
+        prefix: Option<&str>,
+        line_number: usize,
+    ) -> Result<()> {
+        if let Some(prefix) = prefix {
+            write!(out, "{}:", prefix)?;
+        }
+        if self.line_number {
+            write!(out, "{}:", line_number)?;
+        }
+        Ok(())
+    }
+
+    fn write_match<W: Write>(&self, text: &str, out: &mut W) -> Result<()> {
+        if self.color {
+            write!(out, "{}{}{}", COLOR_MATCH, text, COLOR_RESET)?;
+        } else {
+            write!(out, "{}", text)?;
+        }
+        Ok(())
+    }
+}
+
+fn run(args: &Args) -> Result<bool> {
+    let (patterns, files) = args.patterns_and_files();
+    let mut builder = RegexBuilder::new();
+    let syntax = if args.extended_regexp {
+        Syntax::ERE
+    } else {
+        Syntax::PCRE
+    };
+    builder
+        .syntax(syntax)
+        .whole_word(args.word_regexp)
+        .whole_line(args.line_regexp);
+    let nfa = match patterns[..] {
+        [pattern] => builder.build(pattern)?,
+        _ => builder.build_patterns(&patterns)?,
+    };
+    let searcher = Searcher::new(&nfa, args);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if files.is_empty() {
+        return searcher.search(io::stdin().lock(), &mut out, None);
+    }
+
+    let mut matched = false;
+    for path in &files {
+        let file = File::open(path).with_context(|| format!("{}", path.display()))?;
+        let prefix = (files.len() > 1).then(|| path.to_string_lossy());
+        matched |= searcher.search(BufReader::new(file), &mut out, prefix.as_deref())?;
+    }
+    Ok(matched)
 }
 
-fn main() {
-    let pattern1 = "a{1,2}(foo|bar)[ac-z]*";
-    let pattern2 = "foo(baz).*(bar|baz)?";
-    let pattern3 = "[a-z]*";
-
-    let regex1 = parse(pattern1).unwrap();
-    let regex2 = parse(pattern2).unwrap();
-    let regex3 = parse(pattern3).unwrap();
-
-    let nfa1 = NFA::from_regex(&regex1);
-    let nfa2 = NFA::from_regex(&regex2);
-    let nfa3 = NFA::from_regex(&regex3);
-    println!("{:#?}", nfa1);
-    println!("{:#?}", nfa2);
-    println!("{:#?}", nfa3);
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(err) => {
+            eprintln!("agrep: {:#}", err);
+            ExitCode::from(2)
+        }
+    }
 }
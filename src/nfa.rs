@@ -1,30 +1,174 @@
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
 
-use crate::{regex::{self, Regex, RepetitionType}, ast};
+use anyhow::{bail, Result};
 
-#[derive(Debug)]
+use crate::{
+    ast,
+    bitap::BitapMatcher,
+    dfa::DFA,
+    regex::{LineTerminator, Regex, RepetitionType},
+};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Input {
+    Epsilon,
+    Range(char, char),
+    // Any char in these sorted, disjoint ranges, for classes like '[^a]' that would otherwise
+    // take a transition per range.
+    Class(Box<[(char, char)]>),
+    Assert(ast::AnchorType),
+    // Records the current position into a capture slot.
+    Save(usize),
+    // Holds if the lookaround with this index matches at the current position.
+    Lookaround(usize),
+    // Marks the thread as matching the pattern with this index, for `NFA::from_patterns`.
+    Pattern(usize),
+    // Marks the thread as taking this branch of the outermost alternation, for `Match::branch`.
+    Branch(usize),
+}
+
+#[derive(Debug, Hash)]
 pub struct Transition {
-    next: usize,
-    input: Option<(char, char)>,
+    pub(crate) next: usize,
+    pub(crate) input: Input,
 }
 
-#[derive(Debug)]
+impl Transition {
+    pub fn target(&self) -> StateID {
+        self.next
+    }
+
+    pub fn input(&self) -> &Input {
+        &self.input
+    }
+
+    // The ranges of chars this transition consumes, or `None` if it doesn't consume any.
+    pub fn ranges(&self) -> Option<Cow<'_, [(char, char)]>> {
+        match &self.input {
+            Input::Range(start, end) => Some(Cow::Owned(vec![(*start, *end)])),
+            Input::Class(ranges) => Some(Cow::Borrowed(ranges)),
+            _ => None,
+        }
+    }
+
+    // True for the transitions that consume a char, ranges and classes.
+    pub fn is_consuming(&self) -> bool {
+        matches!(self.input, Input::Range(..) | Input::Class(_))
+    }
+}
+
+// The anchor that holds at the mirror image of where `anchor_type` holds, if there is one.
+fn reverse_anchor(anchor_type: &ast::AnchorType) -> Option<ast::AnchorType> {
+    Some(match anchor_type {
+        ast::AnchorType::LineStart => ast::AnchorType::LineEnd,
+        ast::AnchorType::LineEnd => ast::AnchorType::LineStart,
+        ast::AnchorType::WordStart => ast::AnchorType::WordEnd,
+        ast::AnchorType::WordEnd => ast::AnchorType::WordStart,
+        ast::AnchorType::NoWordBefore => ast::AnchorType::NoWordAfter,
+        ast::AnchorType::NoWordAfter => ast::AnchorType::NoWordBefore,
+        ast::AnchorType::TextStart => ast::AnchorType::TextEnd,
+        ast::AnchorType::TextEnd => ast::AnchorType::TextStart,
+        ast::AnchorType::WordBoundary => ast::AnchorType::WordBoundary,
+        ast::AnchorType::NotWordBoundary => ast::AnchorType::NotWordBoundary,
+        ast::AnchorType::TextEndBeforeNewline | ast::AnchorType::KeepOut => return None,
+    })
+}
+
+// Whether sorted, disjoint `ranges` include `c`.
+pub(crate) fn ranges_contain(ranges: &[(char, char)], c: char) -> bool {
+    let index = ranges.partition_point(|&(_, end)| end < c);
+    ranges.get(index).is_some_and(|&(start, _)| start <= c)
+}
+
+#[derive(Debug, Hash)]
 pub struct State {
-    transitions: Vec<Transition>,
+    pub(crate) transitions: Vec<Transition>,
+}
+
+impl State {
+    pub fn transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    // The transition taken on `c`. A state with range transitions has nothing else, and they're
+    // sorted and disjoint, so at most one applies and it can be found by binary search. A state
+    // with a class transition has only that one.
+    fn find_range(&self, c: char) -> Option<&Transition> {
+        if let [transition @ Transition {
+            input: Input::Class(ranges),
+            ..
+        }] = &self.transitions[..]
+        {
+            return ranges_contain(ranges, c).then_some(transition);
+        }
+        let i = self.transitions.partition_point(
+            |transition| matches!(transition.input, Input::Range(_, end) if end < c),
+        );
+        self.transitions
+            .get(i)
+            .filter(|transition| matches!(transition.input, Input::Range(start, _) if start <= c))
+    }
+
+    fn has_sorted_ranges(&self) -> bool {
+        if let [Transition {
+            input: Input::Class(ranges),
+            ..
+        }] = &self.transitions[..]
+        {
+            return ranges.windows(2).all(|pair| pair[0].1 < pair[1].0);
+        }
+        let ranges: Vec<_> = self
+            .transitions
+            .iter()
+            .filter_map(|transition| match transition.input {
+                Input::Range(start, end) => Some((start, end)),
+                _ => None,
+            })
+            .collect();
+        (ranges.is_empty() || ranges.len() == self.transitions.len())
+            && ranges.windows(2).all(|pair| pair[0].1 < pair[1].0)
+    }
 }
 
-type StateID = usize;
+pub type StateID = usize;
 const ZERO: StateID = 0;
 const FINAL: StateID = usize::MAX;
 
 // impl StateID {
 // }
 
+#[derive(Debug)]
+pub struct Lookaround {
+    look: ast::LookaroundType,
+    width: usize,
+    nfa: NFA,
+}
+
 #[derive(Debug)]
 pub struct NFA {
-    states: Vec<State>,
-    initial: StateID,
-    accepting: StateID,
+    pub(crate) states: Vec<State>,
+    pub(crate) initial: StateID,
+    pub(crate) accepting: StateID,
+    // Every accepting state, including `accepting`. Automata built from several patterns end
+    // each one in a state of its own.
+    accepting_set: HashSet<StateID>,
+    groups: usize,
+    lookarounds: Vec<Lookaround>,
+    line_terminator: Option<LineTerminator>,
+    grapheme: bool,
+    word_chars: ast::WordChars,
+    // Set when the pattern is a plain string, which can be searched for directly.
+    literal: Option<String>,
+    // Set when the pattern is short and fixed enough for a Shift-Or matcher.
+    bitap: Option<BitapMatcher>,
+    // Set when the pattern can't match anything, like '[^\0-\u{10FFFF}]'.
+    matches_nothing: bool,
+    // Set when every match starts with '^', so matches can only start at a line start.
+    anchored_start: bool,
 }
 
 impl NFA {
@@ -33,15 +177,115 @@ impl NFA {
             states: Vec::new(),
             initial: ZERO,
             accepting: FINAL,
+            accepting_set: HashSet::new(),
+            groups: 0,
+            lookarounds: Vec::new(),
+            line_terminator: None,
+            grapheme: false,
+            word_chars: ast::WordChars::default(),
+            literal: None,
+            bitap: None,
+            matches_nothing: false,
+            anchored_start: false,
         }
     }
 
     pub fn from_regex(regex: &Regex) -> Self {
         let mut nfa = Self::new();
         NFABuilder::new(&mut nfa, regex).build();
+        nfa.literal = regex.as_literal();
+        nfa.bitap = BitapMatcher::new(regex);
+        nfa.matches_nothing = regex.matches_nothing();
+        nfa.anchored_start = regex.is_anchored_start();
+        nfa
+    }
+
+    // An automaton for the reverse of this one's language, for searching backwards from the end
+    // of a match. Captures aren't recorded. Lookarounds and '\Z' have no mirror image here, so
+    // nothing can match through them.
+    pub fn reversed(&self) -> NFA {
+        let mut nfa = Self::new();
+        for _ in &self.states {
+            nfa.add_state();
+        }
+        for (from, transition) in self.transitions() {
+            let (from, to) = (transition.next, from);
+            let input = match &transition.input {
+                Input::Range(..) | Input::Class(_) => {
+                    // A state can't mix consuming transitions with others, or have overlapping
+                    // ones, so each gets a state of its own.
+                    let state = nfa.add_state();
+                    nfa.add_epsilon_transition(from, state);
+                    nfa.states[state].transitions.push(Transition {
+                        next: to,
+                        input: transition.input.clone(),
+                    });
+                    continue;
+                }
+                Input::Assert(anchor_type) => match reverse_anchor(anchor_type) {
+                    Some(anchor_type) => Input::Assert(anchor_type),
+                    None => continue,
+                },
+                Input::Lookaround(_) => continue,
+                Input::Save(_) => Input::Epsilon,
+                Input::Epsilon | Input::Pattern(_) | Input::Branch(_) => transition.input.clone(),
+            };
+            nfa.states[from]
+                .transitions
+                .push(Transition { next: to, input });
+        }
+        // The VM stops at the accepting state, so it needs one with no way out.
+        let accepting = nfa.add_state();
+        nfa.add_epsilon_transition(self.initial, accepting);
+        nfa.initial = self.accepting;
+        if self.accepting_set.len() > 1 {
+            nfa.initial = nfa.add_state();
+            let mut ends: Vec<_> = self.accepting_set.iter().copied().collect();
+            ends.sort_unstable();
+            for end in ends {
+                nfa.add_epsilon_transition(nfa.initial, end);
+            }
+        }
+        nfa.accepting = accepting;
+        nfa.accepting_set = HashSet::from([accepting]);
+        nfa.groups = self.groups;
+        nfa.line_terminator = self.line_terminator;
+        nfa.grapheme = self.grapheme;
+        nfa.word_chars = self.word_chars;
+        nfa.literal = self
+            .literal
+            .as_ref()
+            .map(|literal| literal.chars().rev().collect());
+        nfa.matches_nothing = self.matches_nothing;
+        nfa
+    }
+
+    // Compiles `pattern`, reusing the automaton from an earlier call with the same pattern.
+    pub fn get_or_compile(pattern: &str) -> Result<std::sync::Arc<NFA>> {
+        crate::cache::get_or_compile(pattern)
+    }
+
+    // Compiles several patterns into one automaton that matches wherever any of them does, as
+    // with `grep -e p1 -e p2`. Each pattern keeps its own inline flags, and capture groups are
+    // numbered across all of them in order.
+    pub fn from_patterns(patterns: &[&str]) -> Result<Self> {
+        crate::RegexBuilder::new().build_patterns(patterns)
+    }
+
+    // Builds a top-level alternation with its branches tagged as separate patterns.
+    pub(crate) fn from_pattern_alternation(regex: &Regex) -> Self {
+        let mut nfa = Self::new();
+        NFABuilder::new(&mut nfa, regex).build_patterns();
+        nfa.matches_nothing = regex.matches_nothing();
+        nfa.anchored_start = regex.is_anchored_start();
         nfa
     }
 
+    // Lowers and compiles an AST built by hand, with the default options.
+    pub fn from_ast(ast: &ast::AST) -> Self {
+        Self::from_regex(&crate::regex::Parser::new().parse(ast))
+    }
+
     fn add_state(&mut self) -> usize {
         let state = State {
             transitions: Vec::new(),
@@ -53,23 +297,287 @@ impl NFA {
     fn add_epsilon_transition(&mut self, from: usize, to: usize) {
         self.states[from].transitions.push(Transition {
             next: to,
-            input: None,
+            input: Input::Epsilon,
         });
     }
 
     fn add_char_transition(&mut self, from: usize, to: usize, input: char) {
         self.states[from].transitions.push(Transition {
             next: to,
-            input: Some((input, input)),
+            input: Input::Range(input, input),
         });
     }
 
     fn add_range_transition(&mut self, from: usize, to: usize, start: char, end: char) {
         self.states[from].transitions.push(Transition {
             next: to,
-            input: Some((start, end)),
+            input: Input::Range(start, end),
+        });
+    }
+
+    fn add_class_transition(&mut self, from: usize, to: usize, ranges: Vec<(char, char)>) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Class(ranges.into_boxed_slice()),
+        });
+    }
+
+    fn add_assert_transition(&mut self, from: usize, to: usize, anchor_type: ast::AnchorType) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Assert(anchor_type),
+        });
+    }
+
+    fn add_save_transition(&mut self, from: usize, to: usize, slot: usize) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Save(slot),
+        });
+    }
+
+    fn add_branch_transition(&mut self, from: usize, to: usize, index: usize) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Branch(index),
+        });
+    }
+
+    fn add_pattern_transition(&mut self, from: usize, to: usize, index: usize) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Pattern(index),
+        });
+    }
+
+    fn add_lookaround_transition(&mut self, from: usize, to: usize, index: usize) {
+        self.states[from].transitions.push(Transition {
+            next: to,
+            input: Input::Lookaround(index),
         });
     }
+
+    // A hash of the language the automaton accepts, so that equivalent patterns such as `a+`
+    // and `aa*` hash equal. Automata with assertions or lookarounds have no plain DFA, and fall
+    // back to hashing their structure.
+    pub fn canonical_hash(&self) -> u64 {
+        if let Some(dfa) = DFA::from_nfa(self) {
+            return dfa.minimize().canonical_hash();
+        }
+        let mut hasher = DefaultHasher::new();
+        self.states.hash(&mut hasher);
+        (self.initial, self.accepting).hash(&mut hasher);
+        let mut accepting: Vec<_> = self.accepting_set.iter().collect();
+        accepting.sort_unstable();
+        accepting.hash(&mut hasher);
+        for lookaround in &self.lookarounds {
+            (&lookaround.look, lookaround.width).hash(&mut hasher);
+            lookaround.nfa.canonical_hash().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // With a line terminator '^' and '$' also match at the start and end of every line.
+    pub fn set_line_terminator(&mut self, line_terminator: Option<LineTerminator>) {
+        self.line_terminator = line_terminator;
+        for lookaround in &mut self.lookarounds {
+            lookaround.nfa.set_line_terminator(line_terminator);
+        }
+    }
+
+    // In grapheme mode every consuming transition takes a whole grapheme cluster, tested by its
+    // first char, and matches only start and end on cluster boundaries.
+    pub fn set_grapheme(&mut self, grapheme: bool) {
+        self.grapheme = grapheme;
+        for lookaround in &mut self.lookarounds {
+            lookaround.nfa.set_grapheme(grapheme);
+        }
+    }
+
+    // Sets the chars the word assertions, like '\b', treat as word chars.
+    pub fn set_word_chars(&mut self, word_chars: ast::WordChars) {
+        self.word_chars = word_chars;
+        for lookaround in &mut self.lookarounds {
+            lookaround.nfa.set_word_chars(word_chars);
+        }
+    }
+
+    // False until the builder has pointed `initial` and `accepting` at real states. Searching an
+    // unbuilt NFA matches nothing, or errors for the fallible searches.
+    pub fn is_built(&self) -> bool {
+        self.initial < self.states.len() && self.accepting < self.states.len()
+    }
+
+    fn ensure_built(&self) -> Result<()> {
+        if !self.is_built() {
+            bail!("Invalid NFA: searched before it was built");
+        }
+        Ok(())
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    // Estimated heap usage in bytes: the states, their transitions and any lookaround automata.
+    pub fn memory_bytes(&self) -> usize {
+        let transitions: usize = self
+            .states
+            .iter()
+            .map(|state| state.transitions.capacity() * size_of::<Transition>())
+            .sum();
+        let lookarounds: usize = self
+            .lookarounds
+            .iter()
+            .map(|lookaround| lookaround.nfa.memory_bytes())
+            .sum();
+        self.states.capacity() * size_of::<State>()
+            + transitions
+            + self.lookarounds.capacity() * size_of::<Lookaround>()
+            + lookarounds
+    }
+
+    // Counts for judging what a compiled pattern will cost to match, for the main automaton and
+    // not its lookarounds.
+    pub fn stats(&self) -> NfaStats {
+        let epsilons = self
+            .transitions()
+            .filter(|(_, transition)| {
+                matches!(
+                    transition.input,
+                    Input::Epsilon | Input::Save(_) | Input::Pattern(_) | Input::Branch(_)
+                )
+            })
+            .count();
+        NfaStats {
+            states: self.states.len(),
+            transitions: self.transitions().count(),
+            epsilons,
+            has_cycles: self.has_cycles(),
+        }
+    }
+
+    // Depth-first search for an edge back to a state still on the path.
+    fn has_cycles(&self) -> bool {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            New,
+            OnPath,
+            Done,
+        }
+        let mut marks = vec![Mark::New; self.states.len()];
+        for root in 0..self.states.len() {
+            if marks[root] != Mark::New {
+                continue;
+            }
+            // Each entry is a state and the index of its next transition to follow.
+            let mut path = vec![(root, 0)];
+            marks[root] = Mark::OnPath;
+            while let Some((state, index)) = path.last_mut() {
+                let Some(transition) = self.states[*state].transitions.get(*index) else {
+                    marks[*state] = Mark::Done;
+                    path.pop();
+                    continue;
+                };
+                *index += 1;
+                match marks[transition.next] {
+                    Mark::OnPath => return true,
+                    Mark::New => {
+                        marks[transition.next] = Mark::OnPath;
+                        path.push((transition.next, 0));
+                    }
+                    Mark::Done => {}
+                }
+            }
+        }
+        false
+    }
+
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+
+    pub fn initial(&self) -> StateID {
+        self.initial
+    }
+
+    // The accepting state, or the first pattern's one if there are several.
+    pub fn accepting(&self) -> StateID {
+        self.accepting
+    }
+
+    pub fn is_accepting(&self, id: StateID) -> bool {
+        id == self.accepting || (self.accepting_set.len() > 1 && self.accepting_set.contains(&id))
+    }
+
+    // Every accepting state, in order.
+    pub fn accepting_states(&self) -> Vec<StateID> {
+        let mut states: Vec<_> = self.accepting_set.iter().copied().collect();
+        states.sort_unstable();
+        states
+    }
+
+    // Every transition in the automaton along with the state it leaves from.
+    pub fn transitions(&self) -> impl Iterator<Item = (StateID, &Transition)> {
+        self.states.iter().enumerate().flat_map(|(from, state)| {
+            state
+                .transitions
+                .iter()
+                .map(move |transition| (from, transition))
+        })
+    }
+
+    // Drops states that can't be reached from the initial state, renumbering the rest in their
+    // original order. The accepting states are always kept so the automaton stays well formed.
+    pub fn prune_unreachable(&mut self) {
+        if !self.is_built() {
+            return;
+        }
+        let mut reachable = vec![false; self.states.len()];
+        let mut stack = vec![self.initial];
+        while let Some(state) = stack.pop() {
+            if reachable[state] {
+                continue;
+            }
+            reachable[state] = true;
+            stack.extend(self.states[state].transitions.iter().map(|t| t.next));
+        }
+        reachable[self.accepting] = true;
+        for &state in &self.accepting_set {
+            reachable[state] = true;
+        }
+
+        let mut ids = vec![FINAL; self.states.len()];
+        let mut next_id = 0;
+        for (state, &keep) in reachable.iter().enumerate() {
+            if keep {
+                ids[state] = next_id;
+                next_id += 1;
+            }
+        }
+        let states = std::mem::take(&mut self.states);
+        self.states = states
+            .into_iter()
+            .zip(reachable)
+            .filter_map(|(state, keep)| keep.then_some(state))
+            .collect();
+        for state in &mut self.states {
+            for transition in &mut state.transitions {
+                transition.next = ids[transition.next];
+            }
+        }
+        self.initial = ids[self.initial];
+        self.accepting = ids[self.accepting];
+        self.accepting_set = self.accepting_set.iter().map(|&state| ids[state]).collect();
+        for lookaround in &mut self.lookarounds {
+            lookaround.nfa.prune_unreachable();
+        }
+    }
+
+    // Number of capture groups, not counting the implicit group 0 for the whole match.
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
 }
 
 struct Component {
@@ -77,14 +585,32 @@ struct Component {
     accepting: usize,
 }
 
+// The branches of the first alternation reached through groups and concatenations, like the
+// methods in '(GET|POST) /'.
+fn outermost_alternation(regex: &Regex) -> Option<&[Regex]> {
+    match regex {
+        Regex::Alternation(regexes) => Some(regexes),
+        Regex::Group(_, regex) => outermost_alternation(regex),
+        Regex::Concat(regexes) => regexes.iter().find_map(outermost_alternation),
+        _ => None,
+    }
+}
+
 struct NFABuilder<'a> {
     nfa: &'a mut NFA,
     regex: &'a Regex,
+    // The branches of the outermost alternation, which are tagged so matches report which one
+    // they took.
+    branches: Option<&'a [Regex]>,
 }
 
 impl<'a> NFABuilder<'a> {
     fn new(nfa: &'a mut NFA, regex: &'a Regex) -> Self {
-        Self { nfa, regex }
+        Self {
+            nfa,
+            regex,
+            branches: outermost_alternation(regex),
+        }
     }
 
     fn build_empty(&mut self) -> Component {
@@ -96,45 +622,66 @@ impl<'a> NFABuilder<'a> {
 
     fn build_literal(&mut self, input: &[char]) -> Component {
         let initial = self.nfa.add_state();
-        let accepting = self.nfa.add_state();
-        self.nfa.add_char_transition(initial, accepting, input[0]);
-        Component { initial, accepting }
+        let mut prev = initial;
+        for &c in input {
+            let next = self.nfa.add_state();
+            self.nfa.add_char_transition(prev, next, c);
+            prev = next;
+        }
+        Component {
+            initial,
+            accepting: prev,
+        }
     }
 
-    // TODO: Support negated classes.
-    fn build_class(&mut self, _negated: bool, items: Vec<ast::ClassItem>) -> Component {
+    // The items are merged into sorted, disjoint ranges, so matching can binary-search them, and
+    // a negated class takes the complement of those.
+    fn build_class(&mut self, negated: bool, items: Vec<ast::ClassItem>) -> Component {
         let initial = self.nfa.add_state();
         let accepting = self.nfa.add_state();
-        for item in items {
-            match item {
-                ast::ClassItem::Ordinary(literal) => {
-                    self.nfa.add_char_transition(initial, accepting, literal);
-                }
-                ast::ClassItem::Range{start, end} => {
-                    self.nfa.add_range_transition(initial, accepting, start, end);
-                }
-                _ => unimplemented!(),
+        let ranges = crate::regex::class_ranges(negated, &items);
+        if ranges.len() > 1 {
+            self.nfa.add_class_transition(initial, accepting, ranges);
+        } else {
+            for (start, end) in ranges {
+                self.nfa
+                    .add_range_transition(initial, accepting, start, end);
             }
         }
         Component { initial, accepting }
     }
 
-    // Note: This should be changed when we add support for starting in the middle of a string.
     fn build_assert(&mut self, anchor_type: &ast::AnchorType) -> Component {
         let initial = self.nfa.add_state();
         let accepting = self.nfa.add_state();
-        match anchor_type {
-            ast::AnchorType::LineStart => {
-                self.nfa.add_epsilon_transition(initial, accepting);
-            }
-            ast::AnchorType::LineEnd => {
-                self.nfa.add_epsilon_transition(initial, accepting);
-            }
+        if *anchor_type == ast::AnchorType::KeepOut {
+            // Overwriting the start slot drops what was matched so far from the match.
+            self.nfa.add_save_transition(initial, accepting, 0);
+        } else {
+            self.nfa
+                .add_assert_transition(initial, accepting, anchor_type.clone());
         }
         Component { initial, accepting }
     }
 
-    fn build_repetition(&mut self, repetition_type: RepetitionType, regex: &Regex) -> Component {
+    // Adds the epsilons out of `from` to `more` and `done`, preferring `more` unless `lazy`.
+    fn add_choice(&mut self, from: usize, more: usize, done: usize, lazy: bool) {
+        let (first, second) = if lazy { (done, more) } else { (more, done) };
+        self.nfa.add_epsilon_transition(from, first);
+        self.nfa.add_epsilon_transition(from, second);
+    }
+
+    fn build_repetition(
+        &mut self,
+        repetition_type: RepetitionType,
+        lazy: bool,
+        regex: &Regex,
+    ) -> Component {
+        if let (RepetitionType::Lower(min @ (0 | 1)), Regex::Class { negated, items }) =
+            (&repetition_type, regex)
+        {
+            return self.build_class_loop(*min == 1, lazy, *negated, items.clone());
+        }
         let initial = self.nfa.add_state();
         let accepting = self.nfa.add_state();
         match repetition_type {
@@ -154,10 +701,10 @@ impl<'a> NFABuilder<'a> {
                     self.nfa.add_epsilon_transition(prev, comp.initial);
                     prev = comp.accepting;
                 }
+                // A greedy repetition prefers looping back over leaving.
                 let comp = self.build_node(regex);
-                self.nfa.add_epsilon_transition(prev, comp.initial);
-                self.nfa.add_epsilon_transition(prev, accepting);
-                self.nfa.add_epsilon_transition(comp.accepting, comp.initial);
+                self.add_choice(prev, comp.initial, accepting, lazy);
+                self.add_choice(comp.accepting, comp.initial, accepting, lazy);
             }
             RepetitionType::Range(min, max) => {
                 let mut prev = initial;
@@ -166,18 +713,71 @@ impl<'a> NFABuilder<'a> {
                     self.nfa.add_epsilon_transition(prev, comp.initial);
                     prev = comp.accepting;
                 }
-                self.nfa.add_epsilon_transition(prev, accepting);
+                // A greedy repetition prefers each optional copy over stopping.
                 for _ in min..max {
                     let comp = self.build_node(regex);
-                    self.nfa.add_epsilon_transition(prev, comp.initial);
-                    self.nfa.add_epsilon_transition(comp.accepting, accepting);
+                    self.add_choice(prev, comp.initial, accepting, lazy);
                     prev = comp.accepting;
                 }
+                self.nfa.add_epsilon_transition(prev, accepting);
             }
         }
         Component { initial, accepting }
     }
 
+    // '.*', '.+' and the like for any class: a state that chooses between taking one more char,
+    // which leads straight back to it, and leaving. That's three states in all, where the
+    // general construction takes four for '.*' and six for '.+'.
+    fn build_class_loop(
+        &mut self,
+        at_least_one: bool,
+        lazy: bool,
+        negated: bool,
+        items: Vec<ast::ClassItem>,
+    ) -> Component {
+        let class = self.build_class(negated, items);
+        let accepting = self.nfa.add_state();
+        // The class leads into the choice, which loops back to the class.
+        let choice = class.accepting;
+        self.add_choice(choice, class.initial, accepting, lazy);
+        let initial = if at_least_one { class.initial } else { choice };
+        Component { initial, accepting }
+    }
+
+    // Group `index` saves its bounds into slots `2 * index` and `2 * index + 1`.
+    fn build_group(&mut self, index: usize, regex: &Regex) -> Component {
+        let initial = self.nfa.add_state();
+        let accepting = self.nfa.add_state();
+        let comp = self.build_node(regex);
+        self.nfa
+            .add_save_transition(initial, comp.initial, 2 * index);
+        self.nfa
+            .add_save_transition(comp.accepting, accepting, 2 * index + 1);
+        self.nfa.groups = self.nfa.groups.max(index);
+        Component { initial, accepting }
+    }
+
+    // The lookaround is compiled to its own automaton, which the VM runs over the `width` chars
+    // before the current position.
+    fn build_lookaround(
+        &mut self,
+        look: &ast::LookaroundType,
+        width: usize,
+        regex: &Regex,
+    ) -> Component {
+        let initial = self.nfa.add_state();
+        let accepting = self.nfa.add_state();
+        self.nfa.lookarounds.push(Lookaround {
+            look: look.clone(),
+            width,
+            nfa: NFA::from_regex(regex),
+        });
+        let index = self.nfa.lookarounds.len() - 1;
+        self.nfa
+            .add_lookaround_transition(initial, accepting, index);
+        Component { initial, accepting }
+    }
+
     fn build_concat(&mut self, regexes: &Vec<Regex>) -> Component {
         let initial = self.nfa.add_state();
         let mut prev = initial;
@@ -192,12 +792,19 @@ impl<'a> NFABuilder<'a> {
         }
     }
 
-    fn build_alternation(&mut self, regexes: &Vec<Regex>) -> Component {
+    fn build_alternation(&mut self, regexes: &[Regex]) -> Component {
         let initial = self.nfa.add_state();
         let accepting = self.nfa.add_state();
-        for regex in regexes {
+        let tagged = self
+            .branches
+            .is_some_and(|branches| std::ptr::eq(branches, regexes));
+        for (index, regex) in regexes.iter().enumerate() {
             let comp = self.build_node(regex);
-            self.nfa.add_epsilon_transition(initial, comp.initial);
+            if tagged {
+                self.nfa.add_branch_transition(initial, comp.initial, index);
+            } else {
+                self.nfa.add_epsilon_transition(initial, comp.initial);
+            }
             self.nfa.add_epsilon_transition(comp.accepting, accepting);
         }
         Component { initial, accepting }
@@ -208,72 +815,2109 @@ impl<'a> NFABuilder<'a> {
             Regex::Empty => self.build_empty(),
             Regex::Literal(input) => self.build_literal(input),
             Regex::Class { negated, items } => self.build_class(*negated, items.clone()),
-            Regex::Assert(anchor_type) => self.build_assert(&anchor_type),
-            Regex::Repetition(repetition_type, regex) => {
-                self.build_repetition(repetition_type.clone(), regex)
+            Regex::Assert(anchor_type) => self.build_assert(anchor_type),
+            Regex::Repetition(repetition_type, lazy, regex) => {
+                self.build_repetition(repetition_type.clone(), *lazy, regex)
             }
+            Regex::Group(index, regex) => self.build_group(*index, regex),
+            Regex::Lookaround { look, width, regex } => self.build_lookaround(look, *width, regex),
             Regex::Concat(regexes) => self.build_concat(regexes),
             Regex::Alternation(regexes) => self.build_alternation(regexes),
         }
     }
 
+    // Builds a top-level alternation with each branch tagged by its index, so matches report
+    // which pattern they came from.
+    fn build_patterns(&mut self) {
+        let Regex::Alternation(regexes) = self.regex else {
+            return self.build();
+        };
+        let initial = self.nfa.add_state();
+        let mut ends = vec![];
+        for (index, regex) in regexes.iter().enumerate() {
+            let comp = self.build_node(regex);
+            self.nfa
+                .add_pattern_transition(initial, comp.initial, index);
+            ends.push(comp.accepting);
+        }
+        // Each pattern ends in an accepting state of its own.
+        let accepting = match ends.first() {
+            Some(&accepting) => accepting,
+            None => self.nfa.add_state(),
+        };
+        self.finish(Component { initial, accepting });
+        self.nfa.accepting_set.extend(ends);
+    }
+
     fn build(&mut self) {
         let comp = self.build_node(self.regex);
+        self.finish(comp);
+    }
+
+    fn finish(&mut self, comp: Component) {
         self.nfa.initial = comp.initial;
         self.nfa.accepting = comp.accepting;
+        self.nfa.accepting_set = HashSet::from([comp.accepting]);
+        debug_assert!(self.nfa.is_built());
+        debug_assert!(self
+            .nfa
+            .transitions()
+            .all(|(_, transition)| transition.next < self.nfa.states.len()));
+        debug_assert!(self.nfa.transitions().all(|(from, transition)| {
+            !matches!(transition.input, Input::Class(_))
+                || self.nfa.states[from].transitions.len() == 1
+        }));
+        debug_assert!(self.nfa.states.iter().all(State::has_sorted_ranges));
     }
 }
 
-struct NFAVM<'a> {
-    nfa: &'a NFA,
-    input: &'a [char],
-    pos: usize,
-    state: StateID,
+impl FromStr for NFA {
+    type Err = ast::ParseError;
+
+    fn from_str(pattern: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(NFA::from_regex(&pattern.parse()?))
+    }
 }
 
-impl<'a> NFAVM<'a> {
-    pub fn new(nfa: &'a NFA, input: &'a [char]) -> Self {
-        Self {
-            nfa,
-            input,
-            pos: 0,
-            state: nfa.initial,
+impl TryFrom<&str> for NFA {
+    type Error = ast::ParseError;
+
+    fn try_from(pattern: &str) -> std::result::Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+// The size of an automaton, from `NFA::stats`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NfaStats {
+    pub states: usize,
+    pub transitions: usize,
+    // Transitions that consume no input and test nothing, which are followed for free.
+    pub epsilons: usize,
+    // Set when some state can reach itself, as unbounded repetitions do.
+    pub has_cycles: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Match {
+    start: usize,
+    end: usize,
+    pattern: usize,
+    branch: Option<usize>,
+}
+
+impl Match {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    // Index of the pattern that matched, for automata built by `NFA::from_patterns`, else 0.
+    pub fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    // Index of the branch of the pattern's outermost alternation that matched, like 1 for
+    // 'POST' in '(GET|POST|PUT) /'. `None` if there's no such alternation, or if it only has
+    // single chars and was merged into a class.
+    pub fn branch(&self) -> Option<usize> {
+        self.branch
+    }
+}
+
+impl NFA {
+    pub fn is_match(&self, input: &str) -> bool {
+        if let Some(literal) = self.literal_fast_path() {
+            return input.contains(literal);
+        }
+        if let Some(bitap) = self.bitap_fast_path() {
+            return bitap.is_match(input);
         }
+        self.is_match_in(&Haystack::new(input))
     }
 
-    fn step(&mut self) -> bool {
-        let mut next_state = None;
-        for transition in &self.nfa.states[self.state].transitions {
-            match transition.input {
-                Some((start, end)) => {
-                    if start <= self.input[self.pos] && self.input[self.pos] <= end {
-                        next_state = Some(transition.next);
-                        if self.run() {
-                            return true;
-                        }
-                    }
-                }
-                None => {
-                    next_state = Some(transition.next);
-                    break;
-                }
-            }
+    pub fn is_full_match(&self, input: &str) -> bool {
+        let chars: Vec<char> = input.chars().collect();
+        NFAVM::new(self, &chars).run(0, true, true).is_some()
+    }
+
+    // Leftmost-first search. Offsets in the returned match are byte offsets into `input`.
+    pub fn find(&self, input: &str) -> Option<Match> {
+        if let Some(literal) = self.literal_fast_path() {
+            return input.find(literal).map(|start| Match {
+                start,
+                end: start + literal.len(),
+                pattern: 0,
+                branch: None,
+            });
         }
-        if let Some(next_state) = next_state {
-            self.state = next_state;
-            self.pos += 1;
-            true
-        } else {
-            false
+        if let Some(bitap) = self.bitap_fast_path() {
+            return bitap.find(input).map(|(start, end)| Match {
+                start,
+                end,
+                pattern: 0,
+                branch: None,
+            });
         }
+        self.find_iter(input).next()
     }
 
-    fn run(&mut self) -> bool {
-        while self.pos < self.input.len() {
-            if !self.step() {
-                return false;
-            }
+    // Whether the pattern can never match, e.g. because it contains a class with no chars.
+    pub fn matches_nothing(&self) -> bool {
+        self.matches_nothing
+    }
+
+    // The string `is_match` and `find` search for directly instead of running the automaton, if
+    // the pattern is a plain string. Grapheme mode needs the automaton to respect clusters.
+    pub fn literal_fast_path(&self) -> Option<&str> {
+        self.literal.as_deref().filter(|_| !self.grapheme)
+    }
+
+    // The Shift-Or matcher `is_match` and `find` use for short fixed patterns like 'ab[0-9]c'.
+    pub fn bitap_fast_path(&self) -> Option<&BitapMatcher> {
+        self.bitap.as_ref().filter(|_| !self.grapheme)
+    }
+
+    // Iterates over successive non-overlapping matches, computing each one on demand.
+    pub fn find_iter<'a>(&'a self, input: &'a str) -> FindMatches<'a> {
+        FindMatches {
+            nfa: self,
+            cursor: Cursor::new(Cow::Owned(Haystack::new(input))),
+        }
+    }
+
+    // Like `find_iter`, but each match must start where the previous one ended, as with the
+    // '\G' anchor, and the first at the start of the input. Iteration stops at the first gap.
+    pub fn find_iter_contiguous<'a>(&'a self, input: &'a str) -> FindMatches<'a> {
+        let mut cursor = Cursor::new(Cow::Owned(Haystack::new(input)));
+        cursor.contiguous = true;
+        FindMatches { nfa: self, cursor }
+    }
+
+    // Like `find_iter`, but matches may overlap: after each match the search resumes one char
+    // past where it started, so 'aa' finds three matches in 'aaaa' rather than two.
+    pub fn find_overlapping_iter<'a>(&'a self, input: &'a str) -> FindMatches<'a> {
+        let mut cursor = Cursor::new(Cow::Owned(Haystack::new(input)));
+        cursor.overlapping = true;
+        FindMatches { nfa: self, cursor }
+    }
+
+    // The lines of `text` that contain a match, as slices of it without their '\n'. A final
+    // '\n' doesn't start another line.
+    pub fn lines_matching<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        text.split_terminator('\n')
+            .filter(move |line| self.is_match(line))
+    }
+
+    pub fn captures<'a>(&'a self, input: &'a str) -> Option<Captures<'a>> {
+        self.captures_iter(input).next()
+    }
+
+    pub fn captures_iter<'a>(&'a self, input: &'a str) -> CaptureMatches<'a> {
+        CaptureMatches {
+            nfa: self,
+            cursor: Cursor::new(Cow::Owned(Haystack::new(input))),
         }
-        true
+    }
+
+    // Replaces every match with `replacement`, in which '$n' stands for the text of group `n`
+    // and '$$' for a literal '$'.
+    pub fn replace_all(&self, input: &str, replacement: &str) -> String {
+        let mut result = String::with_capacity(input.len());
+        let mut last = 0;
+        for caps in self.captures_iter(input) {
+            let m = caps.get(0).unwrap();
+            result.push_str(&input[last..m.start()]);
+            caps.expand(replacement, &mut result);
+            last = m.end();
+        }
+        result.push_str(&input[last..]);
+        result
+    }
+
+    // Like `replace_all`, but reads and writes a line at a time, so only one line is held in
+    // memory. Matches can't span lines.
+    pub fn replace_all_stream<R: BufRead, W: Write>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        replacement: &str,
+    ) -> Result<()> {
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let text = line.strip_suffix('\n').unwrap_or(&line);
+            writer.write_all(self.replace_all(text, replacement).as_bytes())?;
+            writer.write_all(&line.as_bytes()[text.len()..])?;
+            line.clear();
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn trace(&self, input: &str) -> MatchTrace {
+        let haystack = Haystack::new(input);
+        let mut vm = NFAVM::new(self, &haystack.chars).with_trace();
+        let slots = vm.run(0, true, false);
+        let matched = slots.and_then(|slots| {
+            Some(Match {
+                start: haystack.offsets[slots[0]?],
+                end: haystack.offsets[slots[1]?],
+                pattern: vm.pattern,
+                branch: vm.branch,
+            })
+        });
+        MatchTrace {
+            steps: vm.trace.unwrap_or_default(),
+            matched,
+        }
+    }
+
+    // The end of the longest match that begins exactly at byte offset `start`, as a lexer would
+    // want it. Unlike `find`, no later starting positions are tried.
+    pub fn match_at(&self, input: &str, start: usize) -> Option<usize> {
+        if !input.is_char_boundary(start) {
+            return None;
+        }
+        let haystack = Haystack::new(input);
+        let start = haystack.offsets.binary_search(&start).ok()?;
+        let end = NFAVM::new(self, &haystack.chars).run_at(start, false)?;
+        Some(haystack.offsets[end])
+    }
+
+    // The length in bytes of the shortest prefix of `input` that the pattern matches in full.
+    // Unlike `find`, the search stops at the first accepting position rather than carrying on
+    // to the one the match priorities prefer.
+    pub fn shortest_match(&self, input: &str) -> Option<usize> {
+        let haystack = Haystack::new(input);
+        let end = NFAVM::new(self, &haystack.chars).run_at(0, true)?;
+        Some(haystack.offsets[end])
+    }
+
+    pub fn is_match_in(&self, haystack: &Haystack) -> bool {
+        NFAVM::new(self, &haystack.chars)
+            .run(0, false, false)
+            .is_some()
+    }
+
+    pub fn find_in(&self, haystack: &Haystack) -> Option<Match> {
+        self.find_iter_in(haystack).next()
+    }
+
+    pub fn find_iter_in<'a>(&'a self, haystack: &'a Haystack<'a>) -> FindMatches<'a> {
+        FindMatches {
+            nfa: self,
+            cursor: Cursor::new(Cow::Borrowed(haystack)),
+        }
+    }
+
+    pub fn captures_iter_in<'a>(&'a self, haystack: &'a Haystack<'a>) -> CaptureMatches<'a> {
+        CaptureMatches {
+            nfa: self,
+            cursor: Cursor::new(Cow::Borrowed(haystack)),
+        }
+    }
+}
+
+// How byte searches treat input that isn't valid UTF-8.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidUtf8 {
+    // Fail the search.
+    Error,
+    // Drop the invalid bytes, so matches can span them.
+    Skip,
+    // Decode each invalid byte as U+FFFD, which '.' and negated classes match.
+    Replace,
+}
+
+impl NFA {
+    // Searches raw bytes. Offsets in the returned match are byte offsets into `input`.
+    pub fn find_bytes(&self, input: &[u8], invalid: InvalidUtf8) -> Result<Option<Match>> {
+        self.ensure_built()?;
+        let (chars, offsets) = decode_bytes(input, invalid)?;
+        let mut vm = NFAVM::new(self, &chars);
+        let Some(slots) = vm.run(0, false, false) else {
+            return Ok(None);
+        };
+        Ok(slots[0].zip(slots[1]).map(|(start, end)| Match {
+            start: offsets[start],
+            end: offsets[end],
+            pattern: vm.pattern,
+            branch: vm.branch,
+        }))
+    }
+
+    pub fn is_match_bytes(&self, input: &[u8], invalid: InvalidUtf8) -> Result<bool> {
+        Ok(self.find_bytes(input, invalid)?.is_some())
+    }
+}
+
+// Decodes `input` into chars and the byte offset of each, plus one for the end of the input.
+fn decode_bytes(mut input: &[u8], invalid: InvalidUtf8) -> Result<(Vec<char>, Vec<usize>)> {
+    let mut chars = vec![];
+    let mut offsets = vec![];
+    let mut base = 0;
+    loop {
+        let (valid, bad) = match std::str::from_utf8(input) {
+            Ok(valid) => (valid, 0),
+            Err(err) => {
+                let valid = std::str::from_utf8(&input[..err.valid_up_to()]).unwrap();
+                let len = err.error_len().unwrap_or(input.len() - err.valid_up_to());
+                (valid, len)
+            }
+        };
+        for (i, c) in valid.char_indices() {
+            chars.push(c);
+            offsets.push(base + i);
+        }
+        base += valid.len();
+        if bad == 0 {
+            break;
+        }
+        match invalid {
+            InvalidUtf8::Error => bail!("Invalid UTF-8 in input at byte offset {}", base),
+            InvalidUtf8::Skip => {}
+            InvalidUtf8::Replace => {
+                for i in 0..bad {
+                    chars.push(char::REPLACEMENT_CHARACTER);
+                    offsets.push(base + i);
+                }
+            }
+        }
+        base += bad;
+        input = &input[valid.len() + bad..];
+    }
+    offsets.push(base);
+    Ok((chars, offsets))
+}
+
+// Input decoded once up front, so it can be searched by any number of patterns without
+// re-collecting its chars each time.
+#[derive(Clone, Debug)]
+pub struct Haystack<'a> {
+    text: &'a str,
+    chars: Vec<char>,
+    // Byte offset of each char, plus one for the end of the text.
+    offsets: Vec<usize>,
+}
+
+impl<'a> Haystack<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            chars: text.chars().collect(),
+            offsets: text
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(text.len()))
+                .collect(),
+        }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+}
+
+// The groups of a single match. Group 0 is the whole match and always participates.
+#[derive(Clone, Debug)]
+pub struct Captures<'a> {
+    input: &'a str,
+    slots: Vec<Option<usize>>,
+    pattern: usize,
+    branch: Option<usize>,
+}
+
+impl<'a> Captures<'a> {
+    pub fn get(&self, index: usize) -> Option<Match> {
+        match (self.slots.get(2 * index)?, self.slots.get(2 * index + 1)?) {
+            (Some(start), Some(end)) => Some(Match {
+                start: *start,
+                end: *end,
+                pattern: self.pattern,
+                branch: self.branch,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self, index: usize) -> Option<&'a str> {
+        self.get(index).map(|m| &self.input[m.range()])
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.slots.len() / 2
+    }
+
+    // Appends `replacement` to `out` with each '$n' replaced by the text of group `n`, or nothing
+    // if it didn't participate, and each '$$' by '$'.
+    pub fn expand(&self, replacement: &str, out: &mut String) {
+        let mut rest = replacement;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            if digits > 0 {
+                if let Ok(index) = rest[..digits].parse() {
+                    out.push_str(self.text(index).unwrap_or(""));
+                }
+                rest = &rest[digits..];
+            } else if let Some(after) = rest.strip_prefix('$') {
+                out.push('$');
+                rest = after;
+            } else {
+                out.push('$');
+            }
+        }
+        out.push_str(rest);
+    }
+
+    pub fn pattern(&self) -> usize {
+        self.pattern
+    }
+
+    // See `Match::branch`.
+    pub fn branch(&self) -> Option<usize> {
+        self.branch
+    }
+}
+
+pub struct FindMatches<'a> {
+    nfa: &'a NFA,
+    cursor: Cursor<'a>,
+}
+
+impl FindMatches<'_> {
+    // Input positions searched so far. Each match is only searched for when it's asked for, and
+    // the search stops as soon as it's found, so this grows with the matches taken rather than
+    // with the input.
+    pub fn steps(&self) -> usize {
+        self.cursor.steps
+    }
+}
+
+impl<'a> Iterator for FindMatches<'a> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let (slots, pattern, branch) = self.cursor.next(self.nfa)?;
+        Some(Match {
+            start: slots[0]?,
+            end: slots[1]?,
+            pattern,
+            branch,
+        })
+    }
+}
+
+pub struct CaptureMatches<'a> {
+    nfa: &'a NFA,
+    cursor: Cursor<'a>,
+}
+
+impl<'a> Iterator for CaptureMatches<'a> {
+    type Item = Captures<'a>;
+
+    fn next(&mut self) -> Option<Captures<'a>> {
+        let (slots, pattern, branch) = self.cursor.next(self.nfa)?;
+        Some(Captures {
+            input: self.cursor.haystack.text,
+            slots,
+            pattern,
+            branch,
+        })
+    }
+}
+
+// Search state carried between matches: where to search from next and where the last match
+// ended.
+struct Cursor<'a> {
+    haystack: Cow<'a, Haystack<'a>>,
+    pos: usize,
+    last_end: Option<usize>,
+    steps: usize,
+    // Matches must start exactly at `pos`.
+    contiguous: bool,
+    // The search resumes one char after the start of each match rather than at its end.
+    overlapping: bool,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(haystack: Cow<'a, Haystack<'a>>) -> Self {
+        Self {
+            haystack,
+            pos: 0,
+            last_end: None,
+            steps: 0,
+            contiguous: false,
+            overlapping: false,
+        }
+    }
+
+    // Returns the slots of the next match, converted to byte offsets, its pattern and its branch.
+    fn next(&mut self, nfa: &NFA) -> Option<(Slots, usize, Option<usize>)> {
+        let haystack = &*self.haystack;
+        while self.pos <= haystack.chars.len() {
+            let mut vm = NFAVM::new(nfa, &haystack.chars);
+            let slots = vm.run(self.pos, self.contiguous, false);
+            self.steps += vm.steps;
+            let slots = slots?;
+            let (start, end) = (slots[0]?, slots[1]?);
+            // An empty match is never reported right where the previous match ended, and the
+            // search always moves past it so iteration terminates.
+            self.pos = if start == end { end + 1 } else { end };
+            if self.overlapping {
+                // Each match starts at a different position, so none needs skipping.
+                self.pos = start + 1;
+            } else if start == end && self.contiguous {
+                // Nothing can follow an empty match without a gap.
+                self.pos = haystack.chars.len() + 1;
+            }
+            if start == end && self.last_end == Some(end) && !self.overlapping {
+                continue;
+            }
+            self.last_end = Some(end);
+            let slots = slots
+                .into_iter()
+                .map(|slot| slot.map(|pos| haystack.offsets[pos]))
+                .collect();
+            return Some((slots, vm.pattern, vm.branch));
+        }
+        None
+    }
+}
+
+// Capture positions of a thread, as char positions. Slots 0 and 1 hold the bounds of the whole
+// match.
+type Slots = Vec<Option<usize>>;
+
+#[derive(Clone, Debug)]
+struct Thread {
+    state: StateID,
+    slots: Slots,
+    pattern: usize,
+    branch: Option<usize>,
+}
+
+// Chars that continue the grapheme cluster before them: combining marks, variation selectors
+// and zero width joiners. A simplification of the full segmentation rules.
+fn is_grapheme_extend(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{200D}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FE20}'..='\u{FE2F}'
+        | '\u{E0100}'..='\u{E01EF}')
+}
+
+fn is_boundary(input: &[char], grapheme: bool, pos: usize) -> bool {
+    !grapheme
+        || pos == 0
+        || pos >= input.len()
+        || !(is_grapheme_extend(input[pos]) || (input[pos - 1] == '\r' && input[pos] == '\n'))
+}
+
+fn boundary_at_or_after(input: &[char], grapheme: bool, mut pos: usize) -> usize {
+    while !is_boundary(input, grapheme, pos) {
+        pos += 1;
+    }
+    pos
+}
+
+// The position after the char, or cluster in grapheme mode, at `pos`.
+fn next_pos(input: &[char], grapheme: bool, pos: usize) -> usize {
+    boundary_at_or_after(input, grapheme, pos + 1)
+}
+
+// One input position of a traced run: the threads alive at `pos` in priority order, and every
+// transition followed from there, including the epsilon closure of the next position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    pub pos: usize,
+    pub c: Option<char>,
+    pub states: Vec<StateID>,
+    pub transitions: Vec<(StateID, StateID, Input)>,
+}
+
+// A step-by-step record of an anchored match attempt at the start of the input, for seeing where
+// matching went wrong. Step positions are char positions, while `matched` uses byte offsets.
+#[derive(Clone, Debug)]
+pub struct MatchTrace {
+    pub steps: Vec<TraceStep>,
+    pub matched: Option<Match>,
+}
+
+impl MatchTrace {
+    // The position of the char that left no thread alive, if that happened before the input
+    // ran out.
+    pub fn diverged_at(&self) -> Option<usize> {
+        self.steps
+            .windows(2)
+            .find(|pair| !pair[0].states.is_empty() && pair[1].states.is_empty())
+            .map(|pair| pair[0].pos)
+    }
+}
+
+// A Pike VM: every live thread advances in lock-step over the input, so matching is linear in
+// the input length. Threads are kept in priority order, which gives leftmost-first semantics.
+struct NFAVM<'a> {
+    nfa: &'a NFA,
+    input: &'a [char],
+    on_list: Vec<usize>,
+    generation: usize,
+    // Only recorded when tracing, so normal runs don't pay for it.
+    trace: Option<Vec<TraceStep>>,
+    // The pattern and branch of the last match found.
+    pattern: usize,
+    branch: Option<usize>,
+    // Input positions stepped over across all runs.
+    steps: usize,
+}
+
+impl<'a> NFAVM<'a> {
+    pub fn new(nfa: &'a NFA, input: &'a [char]) -> Self {
+        Self {
+            nfa,
+            input,
+            on_list: vec![usize::MAX; nfa.states.len()],
+            generation: 0,
+            trace: None,
+            pattern: 0,
+            branch: None,
+            steps: 0,
+        }
+    }
+
+    fn with_trace(mut self) -> Self {
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    fn record(&mut self, from: StateID, transition: &Transition) {
+        if let Some(step) = self.trace.as_mut().and_then(|trace| trace.last_mut()) {
+            step.transitions
+                .push((from, transition.next, transition.input.clone()));
+        }
+    }
+
+    fn is_assert_satisfied(&self, anchor_type: &ast::AnchorType, pos: usize) -> bool {
+        let is_word = |pos: Option<usize>| {
+            pos.and_then(|pos| self.input.get(pos))
+                .is_some_and(|&c| self.nfa.word_chars.contains(c))
+        };
+        match (anchor_type, self.nfa.line_terminator) {
+            (ast::AnchorType::LineStart, None) => pos == 0,
+            (ast::AnchorType::LineEnd, None) => pos == self.input.len(),
+            (ast::AnchorType::LineStart, Some(t)) => t.is_line_start(self.input, pos),
+            (ast::AnchorType::LineEnd, Some(t)) => t.is_line_end(self.input, pos),
+            (ast::AnchorType::WordBoundary, _) => is_word(pos.checked_sub(1)) != is_word(Some(pos)),
+            (ast::AnchorType::NotWordBoundary, _) => {
+                is_word(pos.checked_sub(1)) == is_word(Some(pos))
+            }
+            (ast::AnchorType::WordStart, _) => !is_word(pos.checked_sub(1)) && is_word(Some(pos)),
+            (ast::AnchorType::WordEnd, _) => is_word(pos.checked_sub(1)) && !is_word(Some(pos)),
+            (ast::AnchorType::NoWordBefore, _) => !is_word(pos.checked_sub(1)),
+            (ast::AnchorType::NoWordAfter, _) => !is_word(Some(pos)),
+            (ast::AnchorType::TextStart, _) => pos == 0,
+            (ast::AnchorType::TextEnd, _) => pos == self.input.len(),
+            (ast::AnchorType::TextEndBeforeNewline, _) => {
+                pos == self.input.len() || (pos + 1 == self.input.len() && self.input[pos] == '\n')
+            }
+            (ast::AnchorType::KeepOut, _) => true,
+        }
+    }
+
+    fn is_lookaround_satisfied(&self, index: usize, pos: usize) -> bool {
+        let lookaround = &self.nfa.lookarounds[index];
+        let matched = pos >= lookaround.width
+            && NFAVM::new(&lookaround.nfa, &self.input[..pos])
+                .run(pos - lookaround.width, true, true)
+                .is_some();
+        match lookaround.look {
+            ast::LookaroundType::Lookbehind => matched,
+            ast::LookaroundType::NegativeLookbehind => !matched,
+        }
+    }
+
+    // Adds `thread` and everything reachable from it through epsilon, save, and satisfied
+    // assertion transitions at `pos`.
+    fn add_thread(&mut self, list: &mut Vec<Thread>, thread: Thread, pos: usize) {
+        if self.on_list[thread.state] == self.generation {
+            return;
+        }
+        self.on_list[thread.state] = self.generation;
+        let nfa = self.nfa;
+        let state = thread.state;
+        let slots = thread.slots.clone();
+        let (pattern, branch) = (thread.pattern, thread.branch);
+        list.push(thread);
+        let transitions = &nfa.states[state].transitions;
+        // A state with range transitions has nothing else, so there's nothing to follow here.
+        if transitions.first().is_some_and(Transition::is_consuming) {
+            return;
+        }
+        for transition in transitions {
+            let mut slots = match &transition.input {
+                Input::Epsilon | Input::Save(_) | Input::Pattern(_) | Input::Branch(_) => {
+                    slots.clone()
+                }
+                Input::Assert(anchor_type) if self.is_assert_satisfied(anchor_type, pos) => {
+                    slots.clone()
+                }
+                Input::Lookaround(index) if self.is_lookaround_satisfied(*index, pos) => {
+                    slots.clone()
+                }
+                Input::Assert(_) | Input::Lookaround(_) | Input::Range(..) | Input::Class(_) => {
+                    continue
+                }
+            };
+            if let Input::Save(slot) = transition.input {
+                slots[slot] = Some(pos);
+            }
+            self.record(state, transition);
+            let pattern = match transition.input {
+                Input::Pattern(pattern) => pattern,
+                _ => pattern,
+            };
+            let branch = match transition.input {
+                Input::Branch(branch) => Some(branch),
+                _ => branch,
+            };
+            let next = Thread {
+                state: transition.next,
+                slots,
+                pattern,
+                branch,
+            };
+            self.add_thread(list, next, pos);
+        }
+    }
+
+    fn step(
+        &mut self,
+        clist: &[Thread],
+        nlist: &mut Vec<Thread>,
+        pos: usize,
+        accept: bool,
+    ) -> Option<Slots> {
+        let nfa = self.nfa;
+        let c = self.input.get(pos).copied();
+        for thread in clist {
+            if nfa.is_accepting(thread.state) {
+                if accept {
+                    // Lower priority threads can no longer win.
+                    let mut slots = thread.slots.clone();
+                    slots[1] = Some(pos);
+                    self.pattern = thread.pattern;
+                    self.branch = thread.branch;
+                    return Some(slots);
+                }
+                continue;
+            }
+            let Some(c) = c else {
+                continue;
+            };
+            if let Some(transition) = nfa.states[thread.state].find_range(c) {
+                self.record(thread.state, transition);
+                let next = Thread {
+                    state: transition.next,
+                    slots: thread.slots.clone(),
+                    pattern: thread.pattern,
+                    branch: thread.branch,
+                };
+                self.add_thread(nlist, next, next_pos(self.input, nfa.grapheme, pos));
+            }
+        }
+        None
+    }
+
+    // Returns the end of the longest match starting exactly at `start`, or of the shortest if
+    // `shortest`, regardless of priority.
+    fn run_at(&mut self, start: usize, shortest: bool) -> Option<usize> {
+        if !self.nfa.is_built() {
+            return None;
+        }
+        let mut longest = None;
+        let mut clist = Vec::new();
+        let mut nlist = Vec::new();
+        self.generation += 1;
+        let thread = Thread {
+            state: self.nfa.initial,
+            slots: vec![None; 2 * (self.nfa.groups + 1)],
+            pattern: 0,
+            branch: None,
+        };
+        let (input, grapheme) = (self.input, self.nfa.grapheme);
+        if !is_boundary(input, grapheme, start) {
+            return None;
+        }
+        self.add_thread(&mut clist, thread, start);
+        let positions = std::iter::successors(Some(start), |&pos| {
+            (pos < input.len()).then(|| next_pos(input, grapheme, pos))
+        });
+        for pos in positions {
+            if clist.is_empty() {
+                break;
+            }
+            if clist
+                .iter()
+                .any(|thread| self.nfa.is_accepting(thread.state))
+            {
+                longest = Some(pos);
+                if shortest {
+                    break;
+                }
+            }
+            self.generation += 1;
+            self.step(&clist, &mut nlist, pos, false);
+            std::mem::swap(&mut clist, &mut nlist);
+            nlist.clear();
+        }
+        longest
+    }
+
+    // Whether a match can start at `pos`. A start-anchored pattern can only match at the start
+    // of the input, or of a line in multiline mode, so no other position is tried.
+    fn can_start(&self, pos: usize) -> bool {
+        match (self.nfa.anchored_start, self.nfa.line_terminator) {
+            (false, _) => true,
+            (true, None) => pos == 0,
+            (true, Some(t)) => t.is_line_start(self.input, pos),
+        }
+    }
+
+    // Returns the slots of the first match found at or after `start`. An `anchored` run only
+    // tries `start` itself, and a `full` run only accepts matches that end with the input.
+    fn run(&mut self, start: usize, anchored: bool, full: bool) -> Option<Slots> {
+        if !self.nfa.is_built() {
+            return None;
+        }
+        let mut matched = None;
+        let mut clist = Vec::new();
+        let mut nlist = Vec::new();
+        self.generation += 1;
+        let (input, grapheme) = (self.input, self.nfa.grapheme);
+        let start = boundary_at_or_after(input, grapheme, start);
+        // Without multiline, nothing can start after the first position.
+        let anchored = anchored || (self.nfa.anchored_start && self.nfa.line_terminator.is_none());
+        let positions = std::iter::successors(Some(start), |&pos| {
+            (pos < input.len()).then(|| next_pos(input, grapheme, pos))
+        });
+        for pos in positions {
+            if let Some(trace) = &mut self.trace {
+                trace.push(TraceStep {
+                    pos,
+                    c: self.input.get(pos).copied(),
+                    states: Vec::new(),
+                    transitions: Vec::new(),
+                });
+            }
+            if matched.is_none() && (!anchored || pos == start) && self.can_start(pos) {
+                let mut slots = vec![None; 2 * (self.nfa.groups + 1)];
+                slots[0] = Some(pos);
+                let thread = Thread {
+                    state: self.nfa.initial,
+                    slots,
+                    pattern: 0,
+                    branch: None,
+                };
+                self.add_thread(&mut clist, thread, pos);
+            }
+            if clist.is_empty() && (matched.is_some() || anchored) {
+                break;
+            }
+            if let Some(step) = self.trace.as_mut().and_then(|trace| trace.last_mut()) {
+                step.states = clist.iter().map(|thread| thread.state).collect();
+            }
+            self.generation += 1;
+            self.steps += 1;
+            let accept = !full || pos == self.input.len();
+            if let Some(slots) = self.step(&clist, &mut nlist, pos, accept) {
+                matched = Some(slots);
+            }
+            std::mem::swap(&mut clist, &mut nlist);
+            nlist.clear();
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_input() -> Result<()> {
+        // The initial closure alone decides whether empty input matches.
+        for (pattern, matches) in [
+            ("a+", false),
+            ("a", false),
+            ("ab[0-9]", false),
+            ("a*", true),
+            ("(a|)", true),
+            ("^$", true),
+            ("\\b", false),
+            ("", true),
+        ] {
+            let nfa = crate::compile(pattern)?;
+            assert_eq!(nfa.is_match(""), matches, "{}", pattern);
+            assert_eq!(nfa.is_full_match(""), matches, "{}", pattern);
+            assert_eq!(nfa.find("").map(|m| m.range()), matches.then_some(0..0));
+            assert_eq!(nfa.find_iter("").count(), matches as usize, "{}", pattern);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_literal() -> Result<()> {
+        let nfa = crate::compile("bar")?;
+        let m = nfa.find("foobarbaz").unwrap();
+        assert_eq!(m.range(), 3..6);
+        assert!(nfa.find("foobaz").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_byte_offsets() -> Result<()> {
+        let nfa = crate::compile("b")?;
+        assert_eq!(nfa.find("ééb").unwrap().range(), 4..5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_iter() -> Result<()> {
+        let nfa = crate::compile("a[0-9]")?;
+        let matches: Vec<_> = nfa.find_iter("a1 b2 a3a4").map(|m| m.range()).collect();
+        assert_eq!(matches, vec![0..2, 6..8, 8..10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_iter_empty() -> Result<()> {
+        let nfa = crate::compile("(x|)")?;
+        let matches: Vec<_> = nfa.find_iter("axb").map(|m| m.range()).collect();
+        assert_eq!(matches, vec![0..0, 1..2, 3..3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_pattern() -> Result<()> {
+        let nfa = crate::compile("")?;
+        assert!(nfa.is_full_match(""));
+        assert!(!nfa.is_full_match("x"));
+        assert!(nfa.is_match(""));
+        assert!(nfa.is_match("x"));
+        let matches: Vec<_> = nfa.find_iter("xy").map(|m| m.range()).collect();
+        assert_eq!(matches, vec![0..0, 1..1, 2..2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_markers() {
+        let regex = Regex::Group(1, Box::new(Regex::Literal(vec!['a'].into_boxed_slice())));
+        let nfa = NFA::from_regex(&regex);
+        let transitions: Vec<_> = nfa
+            .transitions()
+            .map(|(from, transition)| (from, transition.input().clone(), transition.target()))
+            .collect();
+        // The markers are the only transitions besides the literal's, with no epsilons added.
+        assert_eq!(transitions.len(), 3);
+        let (open_from, _, inner_initial) = transitions
+            .iter()
+            .find(|(_, input, _)| *input == Input::Save(2))
+            .unwrap();
+        let (close_from, _, close_to) = transitions
+            .iter()
+            .find(|(_, input, _)| *input == Input::Save(3))
+            .unwrap();
+        let (literal_from, _, literal_to) = transitions
+            .iter()
+            .find(|(_, input, _)| *input == Input::Range('a', 'a'))
+            .unwrap();
+        assert_eq!(*open_from, nfa.initial());
+        assert_eq!(inner_initial, literal_from);
+        assert_eq!(close_from, literal_to);
+        assert_eq!(*close_to, nfa.accepting());
+        assert_eq!(nfa.groups(), 1);
+    }
+
+    #[test]
+    fn test_captures() -> Result<()> {
+        let nfa = crate::compile("([a-z])([0-9])|(_)")?;
+        let caps = nfa.captures("..x1").unwrap();
+        assert_eq!(caps.len(), 4);
+        assert_eq!(caps.get(0).unwrap().range(), 2..4);
+        assert_eq!(caps.text(1), Some("x"));
+        assert_eq!(caps.text(2), Some("1"));
+        assert_eq!(caps.get(3), None);
+        let caps = nfa.captures("a_").unwrap();
+        assert_eq!(caps.get(1), None);
+        assert_eq!(caps.get(3).unwrap().range(), 1..2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_captures_iter() -> Result<()> {
+        let nfa = crate::compile("([0-9]+)-([0-9]+)")?;
+        let pairs: Vec<_> = nfa
+            .captures_iter("1-2 3-4")
+            .map(|caps| (caps.text(1).unwrap(), caps.text(2).unwrap()))
+            .collect();
+        assert_eq!(pairs, vec![("1", "2"), ("3", "4")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_captures_iter_empty() -> Result<()> {
+        let nfa = crate::compile("(x|)")?;
+        let groups: Vec<_> = nfa
+            .captures_iter("axb")
+            .map(|caps| caps.get(1).unwrap().range())
+            .collect();
+        assert_eq!(groups, vec![0..0, 1..2, 3..3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookbehind() -> Result<()> {
+        let nfa = crate::compile("(?<=\\$)\\d+")?;
+        assert_eq!(nfa.find("$100").unwrap().start(), 1);
+        assert!(!nfa.is_match("x100"));
+
+        let nfa = crate::compile("(?<!\\$)\\d")?;
+        assert_eq!(nfa.find("$12").unwrap().range(), 2..3);
+        assert!(!nfa.is_match("$1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookbehind_at_start() -> Result<()> {
+        let nfa = crate::compile("(?<=ab)c")?;
+        assert!(nfa.is_match("abc"));
+        assert!(!nfa.is_match("bc"));
+        assert!(!nfa.is_match("c"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_haystack() -> Result<()> {
+        let haystack = Haystack::new("añb 12 añc");
+        let letters = crate::compile("añ[a-z]")?;
+        let digits = crate::compile("[0-9][0-9]")?;
+        let found: Vec<_> = letters.find_iter_in(&haystack).map(|m| m.range()).collect();
+        assert_eq!(found, vec![0..4, 8..12]);
+        assert_eq!(digits.find_in(&haystack).unwrap().range(), 5..7);
+        assert!(digits.is_match_in(&haystack));
+        let caps = digits.captures_iter_in(&haystack).next().unwrap();
+        assert_eq!(caps.text(0), Some("12"));
+        assert_eq!(haystack.as_str(), "añb 12 añc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_fast_path() -> Result<()> {
+        let nfa = crate::compile("foobar")?;
+        assert_eq!(nfa.literal_fast_path(), Some("foobar"));
+        assert_eq!(nfa.find("a foobar").unwrap().range(), 2..8);
+        assert_eq!(nfa.find("ñfoobar").unwrap().range(), 2..8);
+        assert!(nfa.is_match("xfoobarx"));
+        assert!(!nfa.is_match("fooba"));
+        assert_eq!(
+            nfa.find("foobar"),
+            nfa.find_iter("foobar").next(),
+            "fast path and automaton must agree"
+        );
+
+        for pattern in ["foo.", "fo+", "(foo)", "^foo", "f|o", ""] {
+            assert!(
+                crate::compile(pattern)?.literal_fast_path().is_none(),
+                "{}",
+                pattern
+            );
+        }
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("foo")?;
+        assert!(nfa.literal_fast_path().is_none());
+        let nfa = crate::RegexBuilder::new().grapheme(true).build("e")?;
+        assert!(nfa.literal_fast_path().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch() -> Result<()> {
+        let nfa = crate::compile("(GET|POST|PUT)")?;
+        assert_eq!(nfa.find("POST").unwrap().branch(), Some(1));
+        assert_eq!(nfa.captures("a PUT").unwrap().branch(), Some(2));
+
+        let nfa = crate::compile("^(GET|POST|PUT) /(a|b+)")?;
+        let branches: Vec<_> = ["GET /a", "PUT /bb", "POST /b"]
+            .iter()
+            .map(|input| nfa.find(input).unwrap().branch())
+            .collect();
+        assert_eq!(branches, [Some(0), Some(2), Some(1)]);
+
+        assert_eq!(
+            crate::compile("ab|cd")?.find("xcd").unwrap().branch(),
+            Some(1)
+        );
+        assert_eq!(crate::compile("ab")?.find("ab").unwrap().branch(), None);
+        // Single chars are merged into a class, so there are no branches left.
+        assert_eq!(crate::compile("a|b")?.find("b").unwrap().branch(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_all() -> Result<()> {
+        let nfa = crate::compile("(\\w+)@(\\w+)")?;
+        assert_eq!(
+            nfa.replace_all("mail bob@home or al@work", "$2:$1"),
+            "mail home:bob or work:al"
+        );
+        assert_eq!(nfa.replace_all("a@b", "$$1 $0 $9$"), "$1 a@b $");
+        assert_eq!(nfa.replace_all("none here", "x"), "none here");
+        let nfa = crate::compile("a*")?;
+        assert_eq!(nfa.replace_all("baa", "-"), "-b-");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitap_fast_path() -> Result<()> {
+        let nfa = crate::compile("ab[0-9]c")?;
+        assert!(nfa.bitap_fast_path().is_some());
+        assert_eq!(nfa.find("xab1cab2c").unwrap().range(), 1..5);
+        assert!(nfa.is_match("éab9c"));
+        assert!(!nfa.is_match("abxc"));
+        assert!(crate::compile("ab+")?.bitap_fast_path().is_none());
+        let nfa = crate::RegexBuilder::new().grapheme(true).build("e.")?;
+        assert!(nfa.bitap_fast_path().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches_nothing() -> Result<()> {
+        for pattern in [
+            "[^\0-\u{10FFFF}]",
+            "a[^\0-\u{10FFFF}]",
+            "([^\0-\u{10FFFF}])+",
+        ] {
+            let nfa = crate::compile(pattern)?;
+            assert!(nfa.matches_nothing(), "{}", pattern);
+            assert!(!nfa.is_match("abc"));
+        }
+        for pattern in ["a", "[^a]", "[^\0-\u{10FFFF}]*", "x|[^\0-\u{10FFFF}]", ""] {
+            assert!(!crate::compile(pattern)?.matches_nothing(), "{}", pattern);
+        }
+        // A negated class covering the whole alphabet is empty too.
+        let nfa = crate::RegexBuilder::new()
+            .alphabet(Some("ab"))
+            .build("[^ab]")?;
+        assert!(nfa.matches_nothing());
+        Ok(())
+    }
+
+    #[test]
+    fn test_equivalence_class() {
+        let nfa = NFA::from_ast(&ast::AST::Class {
+            negated: false,
+            items: vec![ast::ClassItem::Equivalence('a')],
+        });
+        assert!(nfa.is_match("a"));
+        assert!(!nfa.is_match("b"));
+        assert!(!nfa.matches_nothing());
+    }
+
+    #[test]
+    fn test_find_iter_contiguous() -> Result<()> {
+        let nfa = crate::compile("a|b")?;
+        let ranges = |input| {
+            nfa.find_iter_contiguous(input)
+                .map(|m| m.range())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ranges("aabb"), [0..1, 1..2, 2..3, 3..4]);
+        assert_eq!(ranges("abxb"), [0..1, 1..2]);
+        assert!(ranges("xab").is_empty());
+        assert_eq!(nfa.find_iter("abxb").count(), 3);
+
+        let nfa = crate::compile("a*")?;
+        let mut matches = nfa.find_iter_contiguous("aab");
+        assert_eq!(matches.next().unwrap().range(), 0..2);
+        assert!(matches.next().is_none());
+        let mut matches = nfa.find_iter_contiguous("baa");
+        assert_eq!(matches.next().unwrap().range(), 0..0);
+        assert!(matches.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_overlapping_iter() -> Result<()> {
+        let nfa = crate::compile("aa")?;
+        let ranges: Vec<_> = nfa
+            .find_overlapping_iter("aaaa")
+            .map(|m| m.range())
+            .collect();
+        assert_eq!(ranges, [0..2, 1..3, 2..4]);
+        assert_eq!(nfa.find_iter("aaaa").count(), 2);
+
+        let nfa = crate::compile("a+")?;
+        let ranges: Vec<_> = nfa
+            .find_overlapping_iter("xaa")
+            .map(|m| m.range())
+            .collect();
+        assert_eq!(ranges, [1..3, 2..3]);
+
+        let nfa = crate::compile("é*")?;
+        let ranges: Vec<_> = nfa.find_overlapping_iter("éb").map(|m| m.range()).collect();
+        assert_eq!(ranges, [0..2, 2..2, 3..3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lines_matching() -> Result<()> {
+        let nfa = crate::compile("o+")?;
+        let text = "foo\nbar\nboo\n\nzoo";
+        let lines: Vec<&str> = nfa.lines_matching(text).collect();
+        assert_eq!(lines, ["foo", "boo", "zoo"]);
+        // The slices borrow from the text.
+        assert_eq!(lines[1].as_ptr(), text[8..].as_ptr());
+
+        let nfa = crate::compile("^$")?;
+        assert_eq!(nfa.lines_matching("a\n\nb\n").count(), 1);
+        assert_eq!(nfa.lines_matching("").count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchored_start_steps() -> Result<()> {
+        let nfa = crate::compile("^foo")?;
+        let input = "x".repeat(10_000);
+        let mut matches = nfa.find_iter(&input);
+        assert!(matches.next().is_none());
+        assert!(matches.steps() <= 1, "{}", matches.steps());
+
+        let input = format!("foo{}", input);
+        let mut matches = nfa.find_iter(&input);
+        assert_eq!(matches.next().unwrap().range(), 0..3);
+        assert!(matches.next().is_none());
+        assert!(matches.steps() < 8, "{}", matches.steps());
+        assert!(nfa.find("xfoo").is_none());
+
+        // In multiline mode every line start is still tried.
+        let nfa = crate::RegexBuilder::new().multiline(true).build("^foo")?;
+        let ranges: Vec<_> = nfa.find_iter("xfoo\nfoo\nfoo").map(|m| m.range()).collect();
+        assert_eq!(ranges, [5..8, 9..12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_iter_lazy() -> Result<()> {
+        let nfa = crate::compile("ab")?;
+        let input = "xab ".repeat(10_000);
+        let mut matches = nfa.find_iter(&input);
+        assert_eq!(matches.next().unwrap().range(), 1..3);
+        assert_eq!(matches.next().unwrap().range(), 5..7);
+        assert!(matches.steps() < 16, "{}", matches.steps());
+        assert_eq!(matches.by_ref().count(), 9_998);
+        assert!(matches.steps() >= input.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_class() -> Result<()> {
+        let nfa = crate::compile("[[:alpha:]]+")?;
+        assert_eq!(nfa.find("12ab3").unwrap().range(), 2..4);
+        let nfa = crate::compile("[[:punct:][:digit:]]")?;
+        assert!(nfa.is_full_match("!"));
+        assert!(nfa.is_full_match("7"));
+        assert!(!nfa.is_full_match("a"));
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("[[:upper:]]")?;
+        assert!(nfa.is_full_match("q"));
+
+        // Named classes are ASCII unless built with the Unicode feature.
+        let unicode = cfg!(feature = "unicode-classes");
+        assert_eq!(crate::compile("[[:alpha:]]")?.is_full_match("ñ"), unicode);
+        assert_eq!(
+            crate::compile("[[:space:]]")?.is_full_match("\u{3000}"),
+            unicode
+        );
+        assert!(!crate::compile("[[:xdigit:]]")?.is_full_match("g"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_shorthand() -> Result<()> {
+        let nfa = crate::compile("[\\d]")?;
+        assert!(nfa.is_full_match("5"));
+        assert!(!nfa.is_full_match("a"));
+
+        let nfa = crate::compile("[\\w.]")?;
+        for input in ["_", ".", "a", "Z", "0"] {
+            assert!(nfa.is_full_match(input), "{}", input);
+        }
+        assert!(!nfa.is_full_match("-"));
+
+        let nfa = crate::compile("[\\d\\s]")?;
+        assert!(nfa.is_full_match("7"));
+        assert!(nfa.is_full_match(" "));
+        assert!(!nfa.is_full_match("x"));
+
+        let nfa = crate::compile("[\\D]")?;
+        assert!(nfa.is_full_match("x"));
+        assert!(nfa.is_full_match("\u{10FFFF}"));
+        assert!(!nfa.is_full_match("3"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_branch_alternation() {
+        let regex = Regex::Alternation(vec![Regex::Literal(vec!['a'].into_boxed_slice())]);
+        let nfa = NFA::from_regex(&regex);
+        assert!(nfa.is_full_match("a"));
+        assert_eq!(nfa.find("bca").unwrap().range(), 2..3);
+        assert!(!nfa.is_match("b"));
+        assert!(!nfa.is_full_match("aa"));
+
+        // It behaves like its branch inside a larger expression too.
+        let regex = Regex::Concat(vec![
+            Regex::Literal(vec!['x'].into_boxed_slice()),
+            Regex::Repetition(RepetitionType::Lower(1), false, Box::new(regex)),
+        ]);
+        let nfa = NFA::from_regex(&regex);
+        assert_eq!(nfa.find("xaab").unwrap().range(), 0..3);
+        assert!(!nfa.is_match("xb"));
+    }
+
+    #[test]
+    fn test_prune_unreachable() {
+        // An alternation without branches leaves its accepting state with no way in.
+        let regex = Regex::Alternation(vec![
+            Regex::Literal(vec!['a'].into_boxed_slice()),
+            Regex::Concat(vec![
+                Regex::Literal(vec!['b'].into_boxed_slice()),
+                Regex::Alternation(vec![]),
+            ]),
+        ]);
+        let mut nfa = NFA::from_regex(&regex);
+        let before = nfa.num_states();
+        nfa.prune_unreachable();
+        assert!(nfa.num_states() < before);
+        assert!(nfa.is_full_match("a"));
+        assert!(!nfa.is_full_match("b"));
+        assert!(!nfa.is_match("xyz"));
+    }
+
+    #[test]
+    fn test_memory_bytes() -> Result<()> {
+        let small = crate::compile("a")?.memory_bytes();
+        assert!(small > 0);
+        assert!(crate::compile("[a-z]{10}(foo|bar)+")?.memory_bytes() > small);
+        assert!(crate::compile("(?<=ab)a")?.memory_bytes() > small);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_keeps_reachable() -> Result<()> {
+        let mut nfa = crate::compile("(a|b)c(?<=bc)")?;
+        let before = nfa.num_states();
+        nfa.prune_unreachable();
+        assert_eq!(nfa.num_states(), before);
+        assert!(nfa.is_match("bc"));
+        assert!(!nfa.is_match("ac"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchors() -> Result<()> {
+        let nfa = crate::compile("^ab$")?;
+        assert!(nfa.is_match("ab"));
+        assert!(!nfa.is_match("xab"));
+        assert!(!nfa.is_match("abx"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_full_match() -> Result<()> {
+        let nfa = crate::compile("a[0-9]")?;
+        assert!(nfa.is_full_match("a1"));
+        assert!(!nfa.is_full_match("a1b"));
+        assert!(nfa.is_match("ba1b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_alternation() -> Result<()> {
+        let nfa = crate::compile("foo|bar")?;
+        assert_eq!(nfa.find("xbarfoo").unwrap().range(), 1..4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted() -> Result<()> {
+        let nfa = crate::compile("\\Qa.b*\\E")?;
+        assert!(nfa.is_full_match("a.b*"));
+        assert!(!nfa.is_match("axbb"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace() -> Result<()> {
+        let nfa = crate::compile("ab")?;
+        let input: Vec<char> = "xab".chars().collect();
+        let mut vm = NFAVM::new(&nfa, &input).with_trace();
+        assert!(vm.run(0, false, false).is_some());
+        let trace = vm.trace.unwrap();
+        assert_eq!(
+            trace.iter().map(|step| step.pos).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        assert_eq!(trace[1].c, Some('a'));
+        for step in &trace {
+            assert!(step.states.contains(&nfa.initial) || step.pos == 3);
+        }
+        // 'a' and 'b' are each consumed exactly once.
+        let consumed = |c| {
+            trace
+                .iter()
+                .flat_map(|step| &step.transitions)
+                .filter(|(_, _, input)| *input == Input::Range(c, c))
+                .count()
+        };
+        assert_eq!(consumed('a'), 1);
+        assert_eq!(consumed('b'), 1);
+        assert!(trace[3].states.contains(&nfa.accepting));
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_terminator() -> Result<()> {
+        let nfa = crate::compile_with_line_terminator(".", LineTerminator::CRLF)?;
+        assert!(nfa.is_match("a"));
+        assert!(!nfa.is_match("\r"));
+        assert!(!nfa.is_match("\n"));
+
+        let nfa = crate::compile_with_line_terminator("b$", LineTerminator::CRLF)?;
+        assert_eq!(nfa.find("ab\r\ncd").unwrap().range(), 1..2);
+        let nfa = crate::compile_with_line_terminator("^c", LineTerminator::CRLF)?;
+        assert_eq!(nfa.find("ab\r\ncd").unwrap().range(), 4..5);
+        // "\r\n" is a single break, so there is no empty line between '\r' and '\n'.
+        let nfa = crate::compile_with_line_terminator("^$", LineTerminator::CRLF)?;
+        assert!(!nfa.is_match("a\r\nb"));
+        assert!(nfa.is_match("a\n\nb"));
+
+        let nfa = crate::compile_with_line_terminator("b$", LineTerminator::LF)?;
+        assert!(!nfa.is_match("ab\r\n"));
+        assert!(nfa.is_match("ab\ncd"));
+        let nfa = crate::compile_with_line_terminator("a.b", LineTerminator::LF)?;
+        assert!(nfa.is_match("a\rb"));
+        assert!(!nfa.is_match("a\nb"));
+
+        let nfa = crate::compile_with_line_terminator("a$", LineTerminator::Any)?;
+        assert!(nfa.is_match("a\u{2028}b"));
+        // Without a terminator the anchors only match at the ends of the input.
+        assert!(!crate::compile("b$")?.is_match("ab\ncd"));
+        Ok(())
+    }
+
+    // The ranges of every range and class transition, in order.
+    fn consumed_ranges(nfa: &NFA) -> Vec<(char, char)> {
+        nfa.transitions()
+            .flat_map(|(_, transition)| transition.ranges().unwrap_or_default().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_class_transition() -> Result<()> {
+        let classes = |pattern| -> Result<Vec<Input>> {
+            Ok(crate::compile(pattern)?
+                .transitions()
+                .filter(|(_, transition)| transition.is_consuming())
+                .map(|(_, transition)| transition.input().clone())
+                .collect())
+        };
+        let ranges = vec![('\0', '`'), ('b', char::MAX)].into_boxed_slice();
+        assert_eq!(classes("[^a]")?, [Input::Class(ranges)]);
+        assert_eq!(classes("[b-d]")?, [Input::Range('b', 'd')]);
+        assert_eq!(classes("\\d\\w")?.len(), 2);
+
+        let nfa = crate::compile("x[^a]+y")?;
+        assert!(nfa.is_full_match("x∂by"));
+        assert!(!nfa.is_match("xay"));
+        assert!(DFA::from_nfa(&nfa).unwrap().is_full_match("xbby"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_negated_class_surrogate_gap() -> Result<()> {
+        // The excluded range runs across the surrogates, so the complement's ranges meet them on
+        // both sides.
+        let nfa = crate::compile("[^\u{D7FE}-\u{E001}]")?;
+        let ranges = consumed_ranges(&nfa);
+        assert_eq!(ranges, [('\0', '\u{D7FD}'), ('\u{E002}', char::MAX)]);
+        for c in ['\u{D7FD}', '\u{E002}', '\0', char::MAX] {
+            assert!(nfa.is_full_match(&c.to_string()), "{:?}", c);
+        }
+        for c in ['\u{D7FE}', '\u{D7FF}', '\u{E000}', '\u{E001}'] {
+            assert!(!nfa.is_match(&c.to_string()), "{:?}", c);
+        }
+
+        // Excluding the chars on either side of the gap leaves no range spanning it.
+        let nfa = crate::compile("[^\u{D7FF}\u{E000}]")?;
+        assert!(nfa.is_full_match("\u{D7FE}"));
+        assert!(nfa.is_full_match("\u{E001}"));
+        assert!(!nfa.is_match("\u{D7FF}\u{E000}"));
+        let nfa = crate::compile("[^a]+")?;
+        assert!(nfa.is_full_match("\u{D7FF}\u{E000}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_class_ranges() -> Result<()> {
+        let nfa = crate::compile("[z_a-f0-9!x-y#%c-k@~^]")?;
+        let ranges = consumed_ranges(&nfa);
+        assert_eq!(
+            ranges,
+            [
+                ('!', '!'),
+                ('#', '#'),
+                ('%', '%'),
+                ('0', '9'),
+                ('@', '@'),
+                ('^', '_'),
+                ('a', 'k'),
+                ('x', 'z'),
+                ('~', '~')
+            ]
+        );
+        for c in "!#%05@^_aekxz~".chars() {
+            assert!(nfa.is_full_match(&c.to_string()), "{}", c);
+        }
+        for c in "\"$&/:?]`lw}".chars() {
+            assert!(!nfa.is_match(&c.to_string()), "{}", c);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transitions() -> Result<()> {
+        let nfa = crate::compile("a|b?")?;
+        let chars: Vec<_> = nfa
+            .transitions()
+            .filter_map(|(from, transition)| match transition.input() {
+                Input::Range(start, end) => Some((from, *start, *end, transition.target())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(chars.len(), 2);
+        for (from, start, _, target) in &chars {
+            assert!(nfa.is_full_match(&start.to_string()));
+            assert!(*from < nfa.num_states() && *target < nfa.num_states());
+        }
+        let epsilons = nfa
+            .transitions()
+            .filter(|(_, transition)| *transition.input() == Input::Epsilon)
+            .count();
+        // The alternation's branches are tagged rather than plain epsilons.
+        let branches = nfa
+            .transitions()
+            .filter(|(_, transition)| matches!(transition.input(), Input::Branch(_)))
+            .count();
+        assert_eq!(branches, 2);
+        assert_eq!(nfa.transitions().count(), chars.len() + epsilons + branches);
+        assert!(nfa.transitions().any(|(from, _)| from == nfa.initial()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_utf8() -> Result<()> {
+        let input = b"ab\xff\xfecd\xe2\x82";
+        let nfa = crate::compile("b..c")?;
+        assert!(nfa.find_bytes(input, InvalidUtf8::Error).is_err());
+        assert_eq!(
+            nfa.find_bytes(input, InvalidUtf8::Replace)?
+                .unwrap()
+                .range(),
+            1..5
+        );
+        // With the invalid bytes dropped, "bc" is adjacent.
+        let nfa = crate::compile("bc")?;
+        assert_eq!(
+            nfa.find_bytes(input, InvalidUtf8::Skip)?.unwrap().range(),
+            1..5
+        );
+        assert!(!nfa.is_match_bytes(input, InvalidUtf8::Replace)?);
+        // A truncated sequence at the end is invalid too.
+        let nfa = crate::compile("d..$")?;
+        assert_eq!(
+            nfa.find_bytes(input, InvalidUtf8::Replace)?
+                .unwrap()
+                .range(),
+            5..8
+        );
+        assert!(crate::compile("é")?.is_match_bytes("café".as_bytes(), InvalidUtf8::Error)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shortest_match() -> Result<()> {
+        assert_eq!(crate::compile("a+")?.shortest_match("aaa"), Some(1));
+        assert_eq!(crate::compile("abc")?.shortest_match("abcd"), Some(3));
+        assert_eq!(crate::compile("a*")?.shortest_match("aaa"), Some(0));
+        assert_eq!(crate::compile("ab|a")?.shortest_match("ab"), Some(1));
+        assert_eq!(crate::compile("é+")?.shortest_match("éé"), Some(2));
+        // Only prefixes count.
+        assert_eq!(crate::compile("b")?.shortest_match("ab"), None);
+        assert_eq!(crate::compile("abc")?.shortest_match("ab"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let stats = crate::compile("a*b")?.stats();
+        assert!(stats.has_cycles);
+        let stats = crate::compile("abc")?.stats();
+        assert!(!stats.has_cycles);
+        assert_eq!(stats.transitions - stats.epsilons, 3);
+        assert_eq!(stats.states, crate::compile("abc")?.num_states());
+
+        for pattern in ["(ab)+", "x(a|b)*", "a{2,}", "(a*)*"] {
+            assert!(crate::compile(pattern)?.stats().has_cycles, "{}", pattern);
+        }
+        for pattern in ["", "a{2,4}", "(a|b)?c", "^a\\b"] {
+            assert!(!crate::compile(pattern)?.stats().has_cycles, "{}", pattern);
+        }
+        let stats = crate::compile("a|b?")?.stats();
+        assert_eq!(
+            stats.transitions,
+            crate::compile("a|b?")?.transitions().count()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_at() -> Result<()> {
+        let nfa = crate::compile("a+")?;
+        assert_eq!(nfa.match_at("aab", 0), Some(2));
+        assert_eq!(nfa.match_at("baa", 0), None);
+        let nfa = crate::compile("a{1,3}")?;
+        assert_eq!(nfa.match_at("aab", 0), Some(2));
+        assert_eq!(nfa.match_at("aab", 1), Some(2));
+        assert_eq!(nfa.match_at("baa", 0), None);
+        assert_eq!(nfa.match_at("baa", 1), Some(3));
+        // The longest alternative wins even though the first one has priority.
+        let nfa = crate::compile("a|ab|abc")?;
+        assert_eq!(nfa.match_at("abcd", 0), Some(3));
+        assert_eq!(nfa.find("abcd").unwrap().end(), 1);
+        let nfa = crate::compile("é|x")?;
+        assert_eq!(nfa.match_at("aéx", 1), Some(3));
+        assert_eq!(nfa.match_at("aéx", 2), None);
+        assert_eq!(nfa.match_at("aéx", 3), Some(4));
+        assert_eq!(nfa.match_at("aéx", 5), None);
+        assert_eq!(crate::compile("")?.match_at("ab", 2), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accessors() -> Result<()> {
+        let nfa = crate::compile("[b-d]")?;
+        let (from, transition) = nfa
+            .transitions()
+            .find(|(_, transition)| transition.ranges().is_some())
+            .unwrap();
+        assert_eq!(transition.ranges().as_deref(), Some(&[('b', 'd')][..]));
+        assert_eq!(transition.target(), nfa.accepting());
+        assert!(nfa.states()[from]
+            .transitions()
+            .iter()
+            .any(|t| t.target() == nfa.accepting()));
+        assert!(nfa.states()[nfa.accepting()].transitions().is_empty());
+
+        // A class transition reports all of its ranges; an epsilon transition consumes nothing.
+        let nfa = crate::compile("[b-dx]")?;
+        let ranges: Vec<_> = nfa
+            .transitions()
+            .filter_map(|(_, transition)| transition.ranges())
+            .collect();
+        assert_eq!(ranges, [&[('b', 'd'), ('x', 'x')][..]]);
+        assert!(crate::compile("a?")?
+            .transitions()
+            .any(|(_, transition)| transition.ranges().is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_grapheme() -> Result<()> {
+        let input = "ae\u{301}b";
+        let nfa = crate::compile("a.b")?;
+        assert!(!nfa.is_match(input));
+        let nfa = crate::RegexBuilder::new().grapheme(true).build("a.b")?;
+        assert!(nfa.is_full_match(input));
+        let nfa = crate::RegexBuilder::new().grapheme(true).build(".")?;
+        let ranges: Vec<_> = nfa.find_iter(input).map(|m| m.range()).collect();
+        assert_eq!(ranges, [0..1, 1..4, 4..5]);
+        assert_eq!(nfa.match_at(input, 1), Some(4));
+        assert_eq!(nfa.match_at(input, 2), None);
+        // "\r\n" is a single cluster too.
+        let nfa = crate::RegexBuilder::new().grapheme(true).build("^.$")?;
+        assert!(nfa.is_match("\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_ast() {
+        use crate::ast::AST;
+        let ast = AST::Concat(vec![
+            AST::Anchor(ast::AnchorType::LineStart),
+            AST::Literal('a'),
+            AST::Group(Box::new(AST::Alternation(vec![
+                AST::Literal('b'),
+                AST::Wildcard,
+            ]))),
+        ]);
+        let nfa = NFA::from_ast(&ast);
+        assert!(nfa.is_full_match("ab"));
+        assert!(nfa.is_full_match("az"));
+        assert!(!nfa.is_match("ba"));
+        assert_eq!(nfa.captures("ax").unwrap().text(1), Some("x"));
+    }
+
+    #[test]
+    fn test_unbuilt() {
+        let mut nfa = NFA::new();
+        assert!(!nfa.is_built());
+        let err = nfa.find_bytes(b"abc", InvalidUtf8::Error).unwrap_err();
+        assert!(err.to_string().contains("before it was built"));
+        assert!(!nfa.is_match("abc"));
+        assert!(!nfa.is_full_match(""));
+        assert_eq!(nfa.find_iter("abc").count(), 0);
+        assert_eq!(nfa.match_at("abc", 0), None);
+        nfa.prune_unreachable();
+        nfa.canonical_hash();
+    }
+
+    #[test]
+    fn test_alternation_repetition() -> Result<()> {
+        let nfa = crate::compile("(a|b){2,3}")?;
+        for input in ["ab", "ba", "aa", "aba", "bbb"] {
+            assert!(nfa.is_full_match(input), "{}", input);
+        }
+        for input in ["", "a", "abab", "abc"] {
+            assert!(!nfa.is_full_match(input), "{}", input);
+        }
+        // Each copy of the group captures separately, and the last one wins.
+        assert_eq!(nfa.captures("ab").unwrap().text(1), Some("b"));
+        let nfa = crate::compile("^(ab|c){2}$")?;
+        assert!(nfa.is_match("abc"));
+        assert!(nfa.is_match("cab"));
+        assert!(!nfa.is_match("abcab"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_repetition() -> Result<()> {
+        for (pattern, min, max) in [("a{2,3}", 2, 3), ("a{0,2}", 0, 2), ("a{3,3}", 3, 3)] {
+            let nfa = crate::compile(pattern)?;
+            for n in 0..6 {
+                let input = "a".repeat(n);
+                let expected = min <= n && n <= max;
+                assert_eq!(
+                    nfa.is_full_match(&input),
+                    expected,
+                    "{} on {:?}",
+                    pattern,
+                    input
+                );
+            }
+            // Optional copies are taken greedily.
+            let found = nfa.find("aaaaa").map(|m| m.range());
+            assert_eq!(found, Some(0..max), "{}", pattern);
+        }
+        assert_eq!(crate::compile("ab?")?.find("abb").unwrap().range(), 0..2);
+        assert_eq!(crate::compile("a{2,3}")?.find("a"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lower_repetition() -> Result<()> {
+        let nfa = crate::compile("a{1,}")?;
+        for input in ["a", "aa", "aaa"] {
+            assert!(nfa.is_full_match(input), "{}", input);
+        }
+        assert!(!nfa.is_full_match(""));
+        let nfa = crate::compile("a{2,}")?;
+        assert!(!nfa.is_full_match("a"));
+        assert!(nfa.is_full_match("aaaa"));
+        let nfa = crate::compile("a*")?;
+        assert!(nfa.is_full_match(""));
+        assert!(nfa.is_full_match("aaa"));
+        // Loops are greedy.
+        assert_eq!(crate::compile("a+")?.find("baaab").unwrap().range(), 1..4);
+        assert_eq!(crate::compile("a*")?.find("aab").unwrap().range(), 0..2);
+        assert_eq!(
+            crate::compile("(ab)+c")?.captures("ababc").unwrap().text(1),
+            Some("ab")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_str() -> Result<()> {
+        let nfa: NFA = "a+b".parse()?;
+        assert_eq!(nfa.find("xaab").unwrap().range(), 1..4);
+        let nfa = NFA::try_from("a+b")?;
+        assert_eq!(nfa.find("xaab").unwrap().range(), 1..4);
+        assert!(matches!(
+            NFA::try_from("a{2"),
+            Err(ast::ParseError::RepetitionRange(_))
+        ));
+        assert!(matches!(
+            "a{2".parse::<NFA>(),
+            Err(ast::ParseError::RepetitionRange(_))
+        ));
+        assert!(matches!(
+            "[a".parse::<NFA>(),
+            Err(ast::ParseError::Class(_))
+        ));
+        assert!(matches!(
+            "(?x)".parse::<NFA>(),
+            Err(ast::ParseError::Flags(_))
+        ));
+        let err = "a\\q".parse::<NFA>().unwrap_err();
+        assert_eq!(
+            err,
+            ast::ParseError::Escape("unknown escape '\\q' at offset 2".into())
+        );
+        assert_eq!(
+            err.to_string(),
+            "Invalid escape: unknown escape '\\q' at offset 2"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_patterns() -> Result<()> {
+        let nfa = NFA::from_patterns(&["foo[0-9]", "(?i)bar", "baz"])?;
+        assert!(nfa.is_match("a foo1 line"));
+        assert!(nfa.is_match("BAR"));
+        assert!(nfa.is_match("baz"));
+        // Flags stay within their own pattern.
+        assert!(!nfa.is_match("BAZ"));
+        assert!(!nfa.is_match("foo"));
+        assert_eq!(nfa.find("xbaz foo2").unwrap().range(), 1..4);
+
+        let nfa = NFA::from_patterns(&["(a)", "(b)"])?;
+        assert_eq!(nfa.captures("b").unwrap().text(2), Some("b"));
+        assert!(NFA::from_patterns(&["a", "b{"]).is_err());
+        assert!(!NFA::from_patterns(&[])?.is_match("a"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accepting_states() -> Result<()> {
+        let nfa = NFA::from_patterns(&["ab", "c+"])?;
+        let accepting = nfa.accepting_states();
+        assert_eq!(accepting.len(), 2);
+        assert!(accepting.contains(&nfa.accepting()));
+        for state in 0..nfa.num_states() {
+            assert_eq!(nfa.is_accepting(state), accepting.contains(&state));
+        }
+        assert!(accepting
+            .iter()
+            .all(|&state| nfa.states()[state].transitions().is_empty()));
+        assert_eq!(nfa.find("xccab").map(|m| m.pattern()), Some(1));
+        assert!(nfa.is_full_match("ab"));
+        assert!(!nfa.is_full_match("abc"));
+        assert!(nfa.reversed().is_full_match("ba"));
+        assert!(DFA::from_nfa(&nfa).unwrap().is_full_match("ccc"));
+
+        let mut pruned = NFA::from_patterns(&["ab", "c+"])?;
+        pruned.prune_unreachable();
+        assert_eq!(pruned.accepting_states().len(), 2);
+        assert!(pruned.is_full_match("c"));
+
+        let nfa = crate::compile("ab|c+")?;
+        assert_eq!(nfa.accepting_states(), [nfa.accepting()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_id() -> Result<()> {
+        let nfa = NFA::from_patterns(&["foo", "ba[rz]", "fo"])?;
+        let ids: Vec<_> = nfa
+            .find_iter("bar foo baz fox")
+            .map(|m| m.pattern())
+            .collect();
+        assert_eq!(ids, [1, 0, 1, 2]);
+        assert_eq!(nfa.captures("xbaz").unwrap().pattern(), 1);
+        assert_eq!(
+            nfa.find_bytes(b"fo", InvalidUtf8::Error)?
+                .unwrap()
+                .pattern(),
+            2
+        );
+        assert_eq!(crate::compile("a|b")?.find("b").unwrap().pattern(), 0);
+        // Single-char patterns aren't fused into one class.
+        let nfa = NFA::from_patterns(&["a", "[bc]"])?;
+        assert_eq!(nfa.find("c").unwrap().pattern(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_trace() -> Result<()> {
+        let nfa = crate::compile("abc")?;
+        let trace = nfa.trace("abx");
+        assert!(trace.matched.is_none());
+        assert_eq!(trace.diverged_at(), Some(2));
+        assert_eq!(trace.steps[2].c, Some('x'));
+        assert!(!trace.steps[2].states.is_empty());
+        assert!(trace.steps[3].states.is_empty());
+
+        // Running out of input isn't a divergence.
+        assert_eq!(nfa.trace("ab").diverged_at(), None);
+        let trace = nfa.trace("abcd");
+        assert_eq!(trace.matched.unwrap().range(), 0..3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_word_boundary() -> Result<()> {
+        let nfa = crate::compile("\\bcat\\b")?;
+        assert_eq!(nfa.find("a cat!").unwrap().range(), 2..5);
+        assert!(nfa.is_match("cat"));
+        assert!(!nfa.is_match("category"));
+        assert!(!nfa.is_match("bobcat_"));
+        let nfa = crate::compile("\\Bat\\B")?;
+        assert!(nfa.is_match("cats"));
+        assert!(!nfa.is_match("at"));
+        assert_eq!(crate::compile("\\b")?.find_iter("ab cd").count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_word_start_end() -> Result<()> {
+        let nfa = crate::compile("\\<cat\\>")?;
+        assert_eq!(nfa.find("a cat").unwrap().range(), 2..5);
+        assert!(!nfa.is_match("cats"));
+        assert!(!crate::compile("\\<cat")?.is_match("scatter"));
+        assert!(crate::compile("cat\\>")?.is_match("bobcat."));
+        // Unlike '\\b', each only holds on its own side of a word.
+        assert_eq!(crate::compile("\\<")?.find_iter("ab cd").count(), 2);
+        assert_eq!(crate::compile("\\>")?.find("ab cd").unwrap().range(), 2..2);
+        assert!(!crate::compile("a\\<")?.is_match("ab"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_end() -> Result<()> {
+        let ends = |pattern, multiline| -> Result<Vec<usize>> {
+            let nfa = crate::RegexBuilder::new()
+                .multiline(multiline)
+                .build(pattern)?;
+            Ok(nfa.find_iter("ab\nabc\n").map(|m| m.start()).collect())
+        };
+        assert_eq!(ends("$", false)?, [7]);
+        assert_eq!(ends("$", true)?, [2, 6, 7]);
+        assert_eq!(ends("\\z", true)?, [7]);
+        assert_eq!(ends("\\Z", false)?, [6, 7]);
+        assert_eq!(ends("\\Z", true)?, [6, 7]);
+
+        assert!(crate::compile("abc\\Z")?.is_match("abc\n"));
+        assert!(!crate::compile("abc\\z")?.is_match("abc\n"));
+        assert!(!crate::compile("abc\\Z")?.is_match("abc\n\n"));
+        assert!(crate::compile("abc\\z")?.is_match("xabc"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reversed() -> Result<()> {
+        let nfa = crate::compile("abc")?;
+        let reversed = nfa.reversed();
+        assert!(reversed.is_full_match("cba"));
+        assert!(!reversed.is_match("abc"));
+        assert!(reversed.reversed().is_full_match("abc"));
+        assert!(!reversed.reversed().is_match("cba"));
+
+        for (pattern, input) in [
+            ("a(b|cd)*e", "acdbe"),
+            ("^x[^a]+y$", "x∂by"),
+            ("\\<foo\\d", "a foo1"),
+            ("\\Aab", "ab"),
+            ("(a*)*b", "aab"),
+        ] {
+            let nfa = crate::compile(pattern)?;
+            let reversed = nfa.reversed();
+            let input_rev: String = input.chars().rev().collect();
+            assert!(nfa.is_match(input), "{}", pattern);
+            assert!(reversed.is_match(&input_rev), "{}", pattern);
+            assert!(reversed.reversed().is_match(input), "{}", pattern);
+        }
+        let reversed = crate::compile("^ab")?.reversed();
+        assert!(reversed.is_match("xba"));
+        assert!(!reversed.is_match("bax"));
+        assert!(!crate::compile("\\Aab")?.reversed().is_match("bax"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_class_loop() -> Result<()> {
+        for pattern in [".*", ".+", "[^a]*", "\\d+?"] {
+            let nfa = crate::compile(pattern)?;
+            assert_eq!(nfa.num_states(), 3, "{}", pattern);
+            assert_eq!(
+                nfa.transitions().filter(|(_, t)| t.is_consuming()).count(),
+                1
+            );
+        }
+        let nfa = crate::compile("(?s).*")?;
+        for input in ["", "a", "∂\nxyz", "\u{10FFFF}"] {
+            assert!(nfa.is_full_match(input), "{:?}", input);
+        }
+        let nfa = crate::compile(".*")?;
+        assert!(nfa.is_full_match("∂xyz"));
+        assert!(!nfa.is_full_match("∂\nxyz"));
+        let nfa = crate::compile(".+")?;
+        assert!(nfa.is_full_match("ab"));
+        assert!(!nfa.is_match(""));
+        let nfa = crate::RegexBuilder::new()
+            .dot_matches_new_line(false)
+            .build("a.*b")?;
+        assert!(nfa.is_full_match("axxb"));
+        assert!(!nfa.is_match("a\nb"));
+        assert_eq!(
+            crate::compile("a.*?b")?.find("aabab").unwrap().range(),
+            0..3
+        );
+        assert_eq!(crate::compile("a.*b")?.find("aabab").unwrap().range(), 0..5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_out() -> Result<()> {
+        let nfa = crate::compile("foo\\Kbar")?;
+        assert_eq!(nfa.find("foobar").unwrap().range(), 3..6);
+        assert!(nfa.find("bar").is_none());
+        // Groups before the '\K' still capture.
+        let nfa = crate::compile("(\\w+)=\\K\\d+")?;
+        let caps = nfa.captures("x key=42").unwrap();
+        assert_eq!(caps.text(0), Some("42"));
+        assert_eq!(caps.text(1), Some("key"));
+        assert_eq!(crate::compile("a\\K")?.find("ba").unwrap().range(), 2..2);
+        Ok(())
     }
 }
@@ -1,6 +1,11 @@
 #![allow(dead_code)]
 
-use crate::ast::{self, AST};
+use std::str::FromStr;
+
+use crate::ast::{self, ParseError, AST};
+use crate::casefold::{self, case_variants};
+use crate::dfa::DFA;
+use crate::nfa::NFA;
 
 type DS = Vec<Regex>;
 
@@ -13,7 +18,14 @@ pub enum Regex {
         items: Vec<ast::ClassItem>,
     },
     Assert(ast::AnchorType),
-    Repetition(RepetitionType, Box<Regex>),
+    // The flag is set when the repetition prefers fewer copies.
+    Repetition(RepetitionType, bool, Box<Regex>),
+    Group(usize, Box<Regex>),
+    Lookaround {
+        look: ast::LookaroundType,
+        width: usize,
+        regex: Box<Regex>,
+    },
     Concat(Vec<Regex>),
     Alternation(Vec<Regex>),
 }
@@ -25,62 +37,718 @@ pub enum RepetitionType {
     Range(u32, u32),
 }
 
+// What counts as a line break. When one is configured, '.' stops matching it and '^'/'$' also
+// match at the start/end of each line.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LineTerminator {
+    LF,
+    // '\r', '\n' or the pair "\r\n", which counts as a single break.
+    CRLF,
+    // Any Unicode line break, with "\r\n" counting as a single break.
+    Any,
+}
+
+impl LineTerminator {
+    pub fn chars(&self) -> &'static [char] {
+        match self {
+            LineTerminator::LF => &['\n'],
+            LineTerminator::CRLF => &['\n', '\r'],
+            LineTerminator::Any => &[
+                '\n', '\u{0B}', '\u{0C}', '\r', '\u{85}', '\u{2028}', '\u{2029}',
+            ],
+        }
+    }
+
+    pub fn is_terminator(&self, c: char) -> bool {
+        self.chars().contains(&c)
+    }
+
+    // True if `pos` sits between the '\r' and '\n' of a break that counts as one.
+    fn splits_crlf(&self, input: &[char], pos: usize) -> bool {
+        *self != LineTerminator::LF
+            && pos > 0
+            && input[pos - 1] == '\r'
+            && input.get(pos) == Some(&'\n')
+    }
+
+    pub fn is_line_start(&self, input: &[char], pos: usize) -> bool {
+        pos == 0 || (self.is_terminator(input[pos - 1]) && !self.splits_crlf(input, pos))
+    }
+
+    pub fn is_line_end(&self, input: &[char], pos: usize) -> bool {
+        pos == input.len() || (self.is_terminator(input[pos]) && !self.splits_crlf(input, pos))
+    }
+}
+
+impl Regex {
+    // Combinators for assembling a `Regex` from parts without going through pattern syntax, so
+    // text is always matched literally:
+    //
+    //   Regex::concat([Regex::literal("a.b"), Regex::star(Regex::literal("c"))])
+
+    // Matches `text` exactly, metachars and all.
+    pub fn literal(text: &str) -> Regex {
+        if text.is_empty() {
+            return Regex::Empty;
+        }
+        Regex::Literal(text.chars().collect())
+    }
+
+    pub fn concat(parts: impl IntoIterator<Item = Regex>) -> Regex {
+        let mut parts: Vec<Regex> = parts.into_iter().collect();
+        match parts.len() {
+            0 => Regex::Empty,
+            1 => parts.pop().unwrap(),
+            _ => Regex::Concat(parts),
+        }
+    }
+
+    // Matches any of `branches`, preferring earlier ones. No branches match nothing.
+    pub fn alt(branches: impl IntoIterator<Item = Regex>) -> Regex {
+        let mut branches: Vec<Regex> = branches.into_iter().collect();
+        match branches.len() {
+            1 => branches.pop().unwrap(),
+            _ => Regex::Alternation(branches),
+        }
+    }
+
+    // Zero or more copies of `inner`, greedily.
+    pub fn star(inner: Regex) -> Regex {
+        Regex::Repetition(RepetitionType::Lower(0), false, Box::new(inner))
+    }
+
+    // True if every match must begin with a '^' assertion.
+    pub fn is_anchored_start(&self) -> bool {
+        match self {
+            Regex::Assert(ast::AnchorType::LineStart) => true,
+            Regex::Group(_, regex) => regex.is_anchored_start(),
+            Regex::Repetition(rep, _, regex) => rep.min() > 0 && regex.is_anchored_start(),
+            Regex::Concat(regexes) => regexes.first().is_some_and(|r| r.is_anchored_start()),
+            Regex::Alternation(regexes) => regexes.iter().all(|r| r.is_anchored_start()),
+            _ => false,
+        }
+    }
+
+    // True if every match must end with a '$' assertion.
+    pub fn is_anchored_end(&self) -> bool {
+        match self {
+            Regex::Assert(ast::AnchorType::LineEnd) => true,
+            Regex::Group(_, regex) => regex.is_anchored_end(),
+            Regex::Repetition(rep, _, regex) => rep.min() > 0 && regex.is_anchored_end(),
+            Regex::Concat(regexes) => regexes.last().is_some_and(|r| r.is_anchored_end()),
+            Regex::Alternation(regexes) => regexes.iter().all(|r| r.is_anchored_end()),
+            _ => false,
+        }
+    }
+
+    // A lower bound on the states `NFA::from_regex` creates, cheap enough to check a size limit
+    // before building anything: a repetition costs one copy of its body per iteration it unrolls.
+    pub fn min_states(&self) -> usize {
+        match self {
+            Regex::Empty | Regex::Class { .. } | Regex::Assert(_) | Regex::Lookaround { .. } => 1,
+            Regex::Literal(chars) => chars.len().max(1),
+            Regex::Group(_, regex) => regex.min_states(),
+            Regex::Repetition(rep, _, regex) => {
+                let copies = match *rep {
+                    RepetitionType::Exact(n) => n as usize,
+                    RepetitionType::Lower(n) => n as usize + 1,
+                    RepetitionType::Range(_, max) => max as usize,
+                };
+                copies.saturating_mul(regex.min_states())
+            }
+            Regex::Concat(regexes) | Regex::Alternation(regexes) => regexes
+                .iter()
+                .fold(0, |sum, regex| sum.saturating_add(regex.min_states())),
+        }
+    }
+
+    // The fewest and most chars any match consumes, the most being `None` if unbounded.
+    pub fn match_length_bounds(&self) -> (usize, Option<usize>) {
+        match self {
+            Regex::Empty | Regex::Assert(_) | Regex::Lookaround { .. } => (0, Some(0)),
+            Regex::Literal(chars) => (chars.len(), Some(chars.len())),
+            Regex::Class { .. } => (1, Some(1)),
+            Regex::Group(_, regex) => regex.match_length_bounds(),
+            Regex::Repetition(rep, _, regex) => {
+                let (min, max) = regex.match_length_bounds();
+                let (n, m) = match *rep {
+                    RepetitionType::Exact(n) => (n, Some(n)),
+                    RepetitionType::Lower(n) => (n, None),
+                    RepetitionType::Range(n, m) => (n, Some(m)),
+                };
+                let max = match (max, m) {
+                    (Some(0), _) => Some(0),
+                    (Some(max), Some(m)) => max.checked_mul(m as usize),
+                    _ => None,
+                };
+                (min.saturating_mul(n as usize), max)
+            }
+            Regex::Concat(regexes) => regexes.iter().fold((0, Some(0)), |(min, max), regex| {
+                let (lo, hi) = regex.match_length_bounds();
+                (
+                    min.saturating_add(lo),
+                    max.zip(hi).and_then(|(a, b)| a.checked_add(b)),
+                )
+            }),
+            Regex::Alternation(regexes) => regexes
+                .iter()
+                .map(Regex::match_length_bounds)
+                .reduce(|(min, max), (lo, hi)| (min.min(lo), max.zip(hi).map(|(a, b)| a.max(b))))
+                .unwrap_or((0, Some(0))),
+        }
+    }
+
+    pub fn min_len(&self) -> usize {
+        self.match_length_bounds().0
+    }
+
+    pub fn max_len(&self) -> Option<usize> {
+        self.match_length_bounds().1
+    }
+
+    // The string this matches if it's nothing but literal chars, like 'foobar'.
+    pub fn as_literal(&self) -> Option<String> {
+        match self {
+            Regex::Literal(chars) => Some(chars.iter().collect()),
+            Regex::Concat(regexes) if !regexes.is_empty() => regexes
+                .iter()
+                .map(|regex| match regex {
+                    Regex::Literal(chars) => Some(chars.iter().collect::<String>()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+
+    // True if no input can match, e.g. because a class is left empty after negation. Empty
+    // classes are the only contradiction detected; anything built from them follows.
+    pub fn matches_nothing(&self) -> bool {
+        match self {
+            Regex::Class { negated, items } => class_ranges(*negated, items).is_empty(),
+            Regex::Empty | Regex::Literal(_) | Regex::Assert(_) => false,
+            Regex::Group(_, regex) => regex.matches_nothing(),
+            Regex::Repetition(rep, _, regex) => rep.min() > 0 && regex.matches_nothing(),
+            Regex::Lookaround { look, regex, .. } => {
+                *look == ast::LookaroundType::Lookbehind && regex.matches_nothing()
+            }
+            Regex::Concat(regexes) => regexes.iter().any(Regex::matches_nothing),
+            Regex::Alternation(regexes) => {
+                !regexes.is_empty() && regexes.iter().all(Regex::matches_nothing)
+            }
+        }
+    }
+
+    // Pairs of branches of the top-level alternation that can match the same string, as indices
+    // in pattern order. Alternations of single chars are fused into a class when lowered, so
+    // overlaps between those go unreported. Branches with assertions or lookarounds are skipped.
+    pub fn overlapping_branches(&self) -> Vec<(usize, usize)> {
+        let branches = match self {
+            Regex::Alternation(branches) => branches,
+            Regex::Group(_, regex) => return regex.overlapping_branches(),
+            _ => return vec![],
+        };
+        let dfas: Vec<Option<DFA>> = branches
+            .iter()
+            .map(|branch| DFA::from_nfa(&NFA::from_regex(branch)))
+            .collect();
+        let mut pairs = vec![];
+        for (i, left) in dfas.iter().enumerate() {
+            for (j, right) in dfas.iter().enumerate().skip(i + 1) {
+                if let (Some(left), Some(right)) = (left, right) {
+                    if left.intersects(right) {
+                        pairs.push((i, j));
+                    }
+                }
+            }
+        }
+        pairs
+    }
+
+    // Strings that appear in every match, for prefiltering input before running the automaton.
+    // Only literals joined by concatenation count, so an alternation contributes nothing.
+    pub fn required_literals(&self) -> Vec<String> {
+        let mut literals = vec![];
+        let mut run = String::new();
+        self.collect_literals(&mut run, &mut literals);
+        flush_literal(&mut run, &mut literals);
+        literals
+    }
+
+    // Chars that appear in every match, in order of first appearance, for cheaply rejecting input
+    // that lacks any of them. Unlike `required_literals`, an alternation contributes the chars
+    // common to all its branches. `None` if no char is required.
+    pub fn required_chars(&self) -> Option<Vec<char>> {
+        let chars = self.collect_required_chars();
+        (!chars.is_empty()).then_some(chars)
+    }
+
+    fn collect_required_chars(&self) -> Vec<char> {
+        let mut chars = vec![];
+        match self {
+            Regex::Literal(literal) => chars.extend(literal.iter()),
+            Regex::Group(_, regex) => chars = regex.collect_required_chars(),
+            Regex::Repetition(rep, _, regex) if rep.min() > 0 => {
+                chars = regex.collect_required_chars()
+            }
+            Regex::Concat(regexes) => {
+                for regex in regexes {
+                    chars.extend(regex.collect_required_chars());
+                }
+            }
+            Regex::Alternation(regexes) => {
+                let mut branches = regexes.iter().map(Regex::collect_required_chars);
+                chars = branches.next().unwrap_or_default();
+                for branch in branches {
+                    chars.retain(|c| branch.contains(c));
+                }
+            }
+            Regex::Empty
+            | Regex::Class { .. }
+            | Regex::Assert(_)
+            | Regex::Lookaround { .. }
+            | Regex::Repetition(..) => {}
+        }
+        let mut seen = vec![];
+        chars.retain(|c| {
+            let first = !seen.contains(c);
+            seen.push(*c);
+            first
+        });
+        chars
+    }
+
+    // Extends `run` with the literal chars that directly follow it, flushing it into `literals`
+    // whenever something variable breaks the run.
+    fn collect_literals(&self, run: &mut String, literals: &mut Vec<String>) {
+        match self {
+            Regex::Literal(chars) => run.extend(chars.iter()),
+            Regex::Empty | Regex::Assert(_) | Regex::Lookaround { .. } => {}
+            Regex::Group(_, regex) => regex.collect_literals(run, literals),
+            Regex::Concat(regexes) => {
+                for regex in regexes {
+                    regex.collect_literals(run, literals);
+                }
+            }
+            // Every match contains at least one copy, but copies aren't adjacent to what's
+            // around them.
+            Regex::Repetition(rep, _, regex) if rep.min() > 0 => {
+                flush_literal(run, literals);
+                regex.collect_literals(run, literals);
+                flush_literal(run, literals);
+            }
+            Regex::Repetition(..) | Regex::Class { .. } | Regex::Alternation(_) => {
+                flush_literal(run, literals);
+            }
+        }
+    }
+}
+
+// The disjoint, sorted ranges of chars a class matches.
+pub(crate) fn class_ranges(negated: bool, items: &[ast::ClassItem]) -> Vec<(char, char)> {
+    let ranges = items
+        .iter()
+        .flat_map(|item| match item {
+            ast::ClassItem::Ordinary(c) | ast::ClassItem::Equivalence(c) => vec![(*c, *c)],
+            ast::ClassItem::Range { start, end } => vec![(*start, *end)],
+            ast::ClassItem::Character(class) => class.ranges().to_vec(),
+            // A collating symbol isn't parsed into the chars it names.
+            ast::ClassItem::Collating => vec![],
+        })
+        .collect();
+    let ranges = ast::merge_ranges(ranges);
+    if negated {
+        ast::complement(&ranges)
+    } else {
+        ranges
+    }
+}
+
+fn flush_literal(run: &mut String, literals: &mut Vec<String>) {
+    if !run.is_empty() && !literals.contains(run) {
+        literals.push(std::mem::take(run));
+    }
+    run.clear();
+}
+
+impl FromStr for Regex {
+    type Err = ParseError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let ast = ast::Parser::new().parse(pattern)?;
+        Ok(Parser::new().parse(&ast))
+    }
+}
+
+impl TryFrom<&str> for Regex {
+    type Error = ParseError;
+
+    fn try_from(pattern: &str) -> Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+impl RepetitionType {
+    pub fn min(&self) -> u32 {
+        match self {
+            RepetitionType::Exact(n) | RepetitionType::Lower(n) | RepetitionType::Range(n, _) => *n,
+        }
+    }
+}
+
 pub struct Parser {
     pos: usize,
+    groups: usize,
+    line_terminator: LineTerminator,
+    dot_matches_new_line: bool,
+    case_insensitive: bool,
+    swap_greed: bool,
+    unbounded_cap: Option<u32>,
+    alphabet: Option<Vec<char>>,
+    warnings: Vec<String>,
 }
 
 impl Parser {
     pub fn new() -> Self {
-        Self { pos: 0 }
+        Self {
+            pos: 0,
+            groups: 0,
+            line_terminator: LineTerminator::LF,
+            dot_matches_new_line: false,
+            case_insensitive: false,
+            swap_greed: false,
+            unbounded_cap: None,
+            alphabet: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    // The terminator '.' excludes unless it matches new lines.
+    pub fn set_line_terminator(&mut self, line_terminator: LineTerminator) {
+        self.line_terminator = line_terminator;
+    }
+
+    // The initial state of the 's' flag.
+    pub fn set_dot_matches_new_line(&mut self, dot_matches_new_line: bool) {
+        self.dot_matches_new_line = dot_matches_new_line;
+    }
+
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    // The initial state of the 'U' flag, which makes bare quantifiers lazy and 'a*?' greedy.
+    pub fn set_swap_greed(&mut self, swap_greed: bool) {
+        self.swap_greed = swap_greed;
+    }
+
+    // Caps unbounded repetitions like '*' and '{n,}' at `cap` repeats, with a warning for each.
+    pub fn set_unbounded_cap(&mut self, cap: Option<u32>) {
+        self.unbounded_cap = cap;
+    }
+
+    // Restricts '.' and negated classes to the chars of `alphabet`, for inputs known to use no
+    // others.
+    pub fn set_alphabet(&mut self, alphabet: Option<&[char]>) {
+        self.alphabet = alphabet.map(|alphabet| {
+            let mut alphabet = alphabet.to_vec();
+            alphabet.sort();
+            alphabet.dedup();
+            alphabet
+        });
+    }
+
+    // Warnings from the last parse.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
     }
 
     pub fn parse(&mut self, ast: &AST) -> Regex {
+        self.groups = 0;
+        self.warnings.clear();
         ParserVM::new(self, ast).parse()
     }
+
+    // Lowers the patterns of a multi-pattern search into the branches of an alternation, which
+    // is never fused so the patterns can still be told apart. Groups are numbered across all of
+    // them.
+    pub fn parse_patterns(&mut self, asts: &[AST]) -> Regex {
+        self.groups = 0;
+        self.warnings.clear();
+        Regex::Alternation(
+            asts.iter()
+                .map(|ast| ParserVM::new(self, ast).parse())
+                .collect(),
+        )
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Items covering the other cases of `item`. ASCII letters in a range are folded as ranges, and
+// everything else char by char.
+fn fold_class_item(item: &ast::ClassItem) -> Vec<ast::ClassItem> {
+    match *item {
+        ast::ClassItem::Ordinary(c) => case_variants(c)
+            .iter()
+            .copied()
+            .map(ast::ClassItem::Ordinary)
+            .collect(),
+        ast::ClassItem::Range { start, end } => {
+            let mut items: Vec<_> = [('a', 'z'), ('A', 'Z')]
+                .into_iter()
+                .filter_map(|(lo, hi)| {
+                    let (start, end) = (start.max(lo), end.min(hi));
+                    (start <= end).then(|| ast::ClassItem::Range {
+                        start: swap_ascii_case(start),
+                        end: swap_ascii_case(end),
+                    })
+                })
+                .collect();
+            for (_, variants) in casefold::variants_in(start, end) {
+                for &v in variants {
+                    if !(start..=end).contains(&v) && !v.is_ascii_alphabetic() {
+                        items.push(ast::ClassItem::Ordinary(v));
+                    }
+                }
+            }
+            items
+        }
+        _ => vec![],
+    }
+}
+
+// Fuses an alternation of single chars and classes, like 'a|b|[c-e]', into one class, which
+// compiles to a single pair of states rather than a branch per alternative.
+fn fuse_alternation(branches: Vec<Regex>) -> Regex {
+    let fusable = branches.len() > 1
+        && branches.iter().all(|branch| match branch {
+            Regex::Literal(literal) => literal.len() == 1,
+            Regex::Class { negated, .. } => !negated,
+            _ => false,
+        });
+    if !fusable {
+        return Regex::Alternation(branches);
+    }
+    let items = branches
+        .into_iter()
+        .flat_map(|branch| match branch {
+            Regex::Literal(literal) => vec![ast::ClassItem::Ordinary(literal[0])],
+            Regex::Class { items, .. } => items,
+            _ => unreachable!(),
+        })
+        .collect();
+    Regex::Class {
+        negated: false,
+        items,
+    }
+}
+
+fn swap_ascii_case(c: char) -> char {
+    if c.is_ascii_lowercase() {
+        c.to_ascii_uppercase()
+    } else {
+        c.to_ascii_lowercase()
+    }
+}
+
+// Flags in effect while lowering. They start out as configured on the `Parser` and are changed
+// by inline flags until the end of the enclosing group.
+#[derive(Clone, Copy)]
+struct FlagState {
+    case_insensitive: bool,
+    dot_matches_new_line: bool,
+    swap_greed: bool,
+}
+
+impl FlagState {
+    fn apply(&mut self, flags: &ast::Flags) {
+        if let Some(case_insensitive) = flags.case_insensitive {
+            self.case_insensitive = case_insensitive;
+        }
+        if let Some(dot_matches_new_line) = flags.dot_matches_new_line {
+            self.dot_matches_new_line = dot_matches_new_line;
+        }
+        if let Some(swap_greed) = flags.swap_greed {
+            self.swap_greed = swap_greed;
+        }
+    }
 }
 
 struct ParserVM<'a> {
     parser: &'a mut Parser,
     ast: &'a AST,
+    flags: FlagState,
 }
 
 impl<'a> ParserVM<'a> {
     fn new(parser: &'a mut Parser, ast: &'a AST) -> Self {
-        Self { parser, ast }
+        let flags = FlagState {
+            case_insensitive: parser.case_insensitive,
+            dot_matches_new_line: parser.dot_matches_new_line,
+            swap_greed: parser.swap_greed,
+        };
+        Self { parser, ast, flags }
     }
 
-    fn parse_node(&mut self, ast: &AST) -> Regex {
-        match ast {
-            AST::Empty => Regex::Empty,
-            AST::Wildcard => Regex::Class {
+    // Lowers `ast` as a group, so flags set inside it don't leak out.
+    fn parse_scoped(&mut self, ast: &AST) -> Regex {
+        let flags = self.flags;
+        let regex = self.parse_node(ast);
+        self.flags = flags;
+        regex
+    }
+
+    // '.' matches anything but the configured line terminator, unless the 's' flag is set.
+    fn parse_wildcard(&self) -> Regex {
+        if let Some(alphabet) = &self.parser.alphabet {
+            let terminators = self.parser.line_terminator.chars();
+            let items = alphabet
+                .iter()
+                .filter(|c| self.flags.dot_matches_new_line || !terminators.contains(c))
+                .copied()
+                .map(ast::ClassItem::Ordinary)
+                .collect();
+            return Regex::Class {
+                negated: false,
+                items,
+            };
+        }
+        if self.flags.dot_matches_new_line {
+            return Regex::Class {
                 negated: false,
                 items: vec![ast::ClassItem::Range {
                     start: 0.into(),
                     end: char::MAX,
                 }],
-            },
-            AST::Literal(literal) => Regex::Literal(vec![*literal].into_boxed_slice()),
-            AST::Class { negated, items } => Regex::Class {
-                negated: negated.clone(),
-                items: items.clone(),
-            },
+            };
+        };
+        let mut chars = self.parser.line_terminator.chars().to_vec();
+        chars.sort();
+        let ranges: Vec<_> = chars.into_iter().map(|c| (c, c)).collect();
+        Regex::Class {
+            negated: false,
+            items: ast::ranges_to_items(&ast::complement(&ranges)),
+        }
+    }
+
+    // Literals become classes over their case variants when matching case-insensitively.
+    fn parse_literal(&self, c: char) -> Regex {
+        let variants = case_variants(c);
+        if !self.flags.case_insensitive || variants.is_empty() {
+            return Regex::Literal(vec![c].into_boxed_slice());
+        }
+        let items = std::iter::once(c)
+            .chain(variants.iter().copied())
+            .map(ast::ClassItem::Ordinary)
+            .collect();
+        Regex::Class {
+            negated: false,
+            items,
+        }
+    }
+
+    fn parse_class(&self, negated: bool, items: &[ast::ClassItem]) -> Regex {
+        let mut items: Vec<_> = items
+            .iter()
+            .flat_map(|item| match item {
+                ast::ClassItem::Character(class) => ast::ranges_to_items(class.ranges()),
+                // Without locale data, '[=a=]' is equivalent to just 'a'.
+                ast::ClassItem::Equivalence(c) => vec![ast::ClassItem::Ordinary(*c)],
+                _ => vec![item.clone()],
+            })
+            .collect();
+        if self.flags.case_insensitive {
+            let folded: Vec<_> = items.iter().flat_map(fold_class_item).collect();
+            items.extend(folded);
+        }
+        // Within an alphabet, the complement only needs the alphabet's other chars.
+        if let (true, Some(alphabet)) = (negated, &self.parser.alphabet) {
+            let contains = |c: char| {
+                items.iter().any(|item| match *item {
+                    ast::ClassItem::Ordinary(o) => o == c,
+                    ast::ClassItem::Range { start, end } => start <= c && c <= end,
+                    _ => false,
+                })
+            };
+            let items = alphabet
+                .iter()
+                .copied()
+                .filter(|&c| !contains(c))
+                .map(ast::ClassItem::Ordinary)
+                .collect();
+            return Regex::Class {
+                negated: false,
+                items,
+            };
+        }
+        Regex::Class { negated, items }
+    }
+
+    // 'n or more', unless unbounded repetitions are capped. A cap below `n` leaves exactly `n`.
+    fn parse_lower(&mut self, n: u32) -> RepetitionType {
+        let Some(cap) = self.parser.unbounded_cap else {
+            return RepetitionType::Lower(n);
+        };
+        let max = cap.max(n);
+        self.parser.warnings.push(format!(
+            "unbounded repetition of at least {} capped at {}",
+            n, max
+        ));
+        RepetitionType::Range(n, max)
+    }
+
+    fn parse_node(&mut self, ast: &AST) -> Regex {
+        match ast {
+            AST::Empty => Regex::Empty,
+            AST::Wildcard => self.parse_wildcard(),
+            AST::Literal(literal) => self.parse_literal(*literal),
+            AST::Class { negated, items } => self.parse_class(*negated, items),
             AST::Anchor(anchor_type) => Regex::Assert(anchor_type.clone()),
-            AST::Repetition(repetition_type, ast) => {
+            AST::Repetition(repetition_type, lazy, ast) => {
                 let rep = match repetition_type {
                     ast::RepetitionType::ZeroOrOne => RepetitionType::Range(0, 1),
-                    ast::RepetitionType::ZeroOrMore => RepetitionType::Lower(0),
-                    ast::RepetitionType::OneOrMore => RepetitionType::Lower(1),
+                    ast::RepetitionType::ZeroOrMore => self.parse_lower(0),
+                    ast::RepetitionType::OneOrMore => self.parse_lower(1),
                     ast::RepetitionType::Exact(n) => RepetitionType::Exact(*n),
-                    ast::RepetitionType::Lower(n) => RepetitionType::Lower(*n),
+                    ast::RepetitionType::Lower(n) => self.parse_lower(*n),
                     ast::RepetitionType::Range(n, m) => RepetitionType::Range(*n, *m),
                 };
-                Regex::Repetition(rep, Box::new(self.parse_node(ast)))
+                let lazy = *lazy != self.flags.swap_greed;
+                Regex::Repetition(rep, lazy, Box::new(self.parse_node(ast)))
             }
+            AST::Lookaround(look, ast) => Regex::Lookaround {
+                look: look.clone(),
+                width: ast
+                    .fixed_width()
+                    .expect("lookbehind must have a fixed width"),
+                regex: Box::new(self.parse_scoped(ast)),
+            },
             AST::Concat(ast) => Regex::Concat(ast.iter().map(|ast| self.parse_node(ast)).collect()),
             AST::Alternation(ast) => {
-                Regex::Alternation(ast.iter().map(|ast| self.parse_node(ast)).collect())
+                fuse_alternation(ast.iter().map(|ast| self.parse_node(ast)).collect())
+            }
+            // Groups are numbered by the position of their '(', i.e. in pre-order.
+            AST::Group(ast) => {
+                self.parser.groups += 1;
+                let index = self.parser.groups;
+                Regex::Group(index, Box::new(self.parse_scoped(ast)))
+            }
+            AST::FlagGroup(flags, ast) => {
+                let outer = self.flags;
+                self.flags.apply(flags);
+                let regex = self.parse_node(ast);
+                self.flags = outer;
+                regex
+            }
+            AST::Flags(flags) => {
+                self.flags.apply(flags);
+                Regex::Empty
             }
-            AST::Group(ast) => self.parse_node(ast),
         }
     }
 
@@ -88,3 +756,206 @@ impl<'a> ParserVM<'a> {
         self.parse_node(self.ast)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_anchored() -> Result<()> {
+        let regex = crate::parse("^abc")?;
+        assert!(regex.is_anchored_start());
+        assert!(!regex.is_anchored_end());
+
+        let regex = crate::parse("abc$")?;
+        assert!(!regex.is_anchored_start());
+        assert!(regex.is_anchored_end());
+
+        let regex = crate::parse("abc")?;
+        assert!(!regex.is_anchored_start());
+        assert!(!regex.is_anchored_end());
+        Ok(())
+    }
+
+    #[test]
+    fn test_anchored_nested() -> Result<()> {
+        assert!(crate::parse("(^a|^b)c")?.is_anchored_start());
+        assert!(!crate::parse("(^a|b)c")?.is_anchored_start());
+        assert!(!crate::parse("(^a)?b")?.is_anchored_start());
+        assert!(crate::parse("a(b$)")?.is_anchored_end());
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_from() -> Result<()> {
+        assert!(super::Regex::try_from("^ab")?.is_anchored_start());
+        assert!(super::Regex::try_from("a{2").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuse_alternation() -> Result<()> {
+        match crate::parse("a|b|c")? {
+            Regex::Class { negated, items } => {
+                assert!(!negated);
+                assert_eq!(
+                    items,
+                    [
+                        ast::ClassItem::Ordinary('a'),
+                        ast::ClassItem::Ordinary('b'),
+                        ast::ClassItem::Ordinary('c')
+                    ]
+                );
+            }
+            regex => panic!("expected a class, got {:?}", regex),
+        }
+        assert!(matches!(crate::parse("x|[c-e]|\\d")?, Regex::Class { .. }));
+        assert!(matches!(crate::parse("a|bc")?, Regex::Alternation(_)));
+        assert!(matches!(crate::parse("a|[^b]")?, Regex::Alternation(_)));
+        assert!(matches!(crate::parse("a|(b)")?, Regex::Alternation(_)));
+
+        let nfa = crate::compile("a|b|[c-e]")?;
+        for input in ["a", "b", "d"] {
+            assert!(nfa.is_full_match(input), "{}", input);
+        }
+        assert!(!nfa.is_match("f"));
+        assert!(nfa.num_states() < crate::compile("a|b|[c-e]|fg")?.num_states());
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_length_bounds() -> Result<()> {
+        let bounds = |pattern| crate::parse(pattern).map(|regex| regex.match_length_bounds());
+        assert_eq!(bounds("a{2,4}")?, (2, Some(4)));
+        assert_eq!(bounds("a+b")?, (2, None));
+        assert_eq!(bounds("")?, (0, Some(0)));
+        assert_eq!(bounds("^ab$")?, (2, Some(2)));
+        assert_eq!(bounds("(ab|c)d?")?, (1, Some(3)));
+        assert_eq!(bounds("(ab){3}[xy]*")?, (6, None));
+        assert_eq!(bounds("(?<=ab)c")?, (1, Some(1)));
+        assert_eq!(bounds("(a{0}|b)*c")?, (1, None));
+        assert_eq!(bounds("(a{0})*c")?, (1, Some(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_max_len() -> Result<()> {
+        let lens = |pattern| crate::parse(pattern).map(|regex| (regex.min_len(), regex.max_len()));
+        assert_eq!(lens("abc")?, (3, Some(3)));
+        assert_eq!(lens("a{2,4}")?, (2, Some(4)));
+        assert_eq!(lens("a*")?, (0, None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbounded_cap_warnings() -> Result<()> {
+        let ast = ast::Parser::new().parse("a+b{2}c{3,}")?;
+        let mut parser = Parser::new();
+        assert!(matches!(
+            parser.parse(&ast),
+            Regex::Concat(regexes)
+                if matches!(regexes[0], Regex::Repetition(RepetitionType::Lower(1), ..))
+        ));
+        assert!(parser.warnings().is_empty());
+        parser.set_unbounded_cap(Some(4));
+        assert!(matches!(
+            parser.parse(&ast),
+            Regex::Concat(regexes)
+                if matches!(regexes[2], Regex::Repetition(RepetitionType::Range(3, 4), ..))
+        ));
+        assert_eq!(
+            parser.warnings(),
+            [
+                "unbounded repetition of at least 1 capped at 4",
+                "unbounded repetition of at least 3 capped at 4"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_required_literals() -> Result<()> {
+        let literals = |pattern| crate::parse(pattern).map(|regex| regex.required_literals());
+        assert_eq!(literals("foo.*bar")?, ["foo", "bar"]);
+        assert!(literals("(cat|dog)")?.is_empty());
+        assert_eq!(literals("a+")?, ["a"]);
+        assert!(literals("a*")?.is_empty());
+        assert_eq!(literals("^x(yz)[0-9]w$")?, ["xyz", "w"]);
+        assert_eq!(literals("(ab){2}c")?, ["ab", "c"]);
+        assert_eq!(literals("a.a")?, ["a"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_combinators() {
+        let lit = Regex::literal;
+        let nfa = NFA::from_regex(&Regex::alt([lit("a"), lit("b")]));
+        assert!(nfa.is_full_match("a"));
+        assert!(nfa.is_full_match("b"));
+        assert!(!nfa.is_match("c"));
+
+        // Metachars in the parts stay literal.
+        let regex = Regex::concat([lit("a.b"), Regex::star(Regex::alt([lit("x+"), lit("")]))]);
+        let nfa = NFA::from_regex(&regex);
+        assert!(nfa.is_full_match("a.bx+x+"));
+        assert!(!nfa.is_match("axb"));
+        assert!(!nfa.is_full_match("a.bxx"));
+
+        assert!(NFA::from_regex(&Regex::concat([])).is_full_match(""));
+        assert!(!NFA::from_regex(&Regex::alt([])).is_match("a"));
+        assert!(NFA::from_regex(&Regex::star(lit("ab"))).is_full_match("abab"));
+    }
+
+    #[test]
+    fn test_required_chars() -> Result<()> {
+        let chars = |pattern| crate::parse(pattern).map(|regex| regex.required_chars());
+        assert_eq!(chars("ab(c|d)")?, Some(vec!['a', 'b']));
+        assert_eq!(chars("a?b")?, Some(vec!['b']));
+        assert_eq!(chars("foo.*bar")?, Some(vec!['f', 'o', 'b', 'a', 'r']));
+        assert_eq!(chars("(cat|act)s")?, Some(vec!['c', 'a', 't', 's']));
+        assert_eq!(chars("(x|y)+")?, None);
+        assert_eq!(chars("a*[bc]")?, None);
+        assert_eq!(chars("")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_branches() -> Result<()> {
+        let overlaps = |pattern| crate::parse(pattern).map(|regex| regex.overlapping_branches());
+        assert_eq!(overlaps("if|[a-z]+")?, vec![(0, 1)]);
+        assert_eq!(overlaps("[0-9]|[a-z]")?, vec![]);
+        assert_eq!(overlaps("ab|cd|a[b-z]|x*")?, vec![(0, 2)]);
+        assert_eq!(overlaps("(?:a+|b|aa)")?, vec![(0, 2)]);
+        assert_eq!(overlaps("ab")?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_folding() -> anyhow::Result<()> {
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("k")?;
+        assert!(nfa.is_match("\u{212A}"));
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("ß")?;
+        assert!(nfa.is_match("ẞ"));
+        assert!(!nfa.is_match("ss"));
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("[σ]")?;
+        assert!(nfa.is_match("Σ"));
+        assert!(nfa.is_match("ς"));
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("[a-z]")?;
+        assert!(nfa.is_match("\u{212A}"));
+        assert!(nfa.is_match("Q"));
+        let nfa = crate::RegexBuilder::new()
+            .case_insensitive(true)
+            .build("i")?;
+        assert!(!nfa.is_match("İ"));
+        Ok(())
+    }
+}
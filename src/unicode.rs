@@ -0,0 +1,43 @@
+// Unicode versions of the POSIX named classes, built on first use from the standard library's
+// char properties. Classes without a matching property, like '[:punct:]', stay ASCII.
+
+use std::sync::OnceLock;
+
+use crate::ast::{ranges_of, NamedClass};
+
+pub(crate) fn named_class_ranges(class: &NamedClass) -> Option<&'static [(char, char)]> {
+    static TABLES: [OnceLock<Vec<(char, char)>>; 8] = [const { OnceLock::new() }; 8];
+    let (index, property): (usize, fn(char) -> bool) = match class {
+        NamedClass::Alnum => (0, char::is_alphanumeric),
+        NamedClass::Alpha => (1, char::is_alphabetic),
+        NamedClass::Blank => (2, is_blank),
+        NamedClass::Cntrl => (3, char::is_control),
+        // std only has the wider Numeric property, which includes things like '½'.
+        NamedClass::Digit => (4, char::is_numeric),
+        NamedClass::Lower => (5, char::is_lowercase),
+        NamedClass::Space => (6, char::is_whitespace),
+        NamedClass::Upper => (7, char::is_uppercase),
+        _ => return None,
+    };
+    Some(TABLES[index].get_or_init(|| ranges_of(property)))
+}
+
+// Horizontal whitespace, so anything but the line and paragraph separators.
+fn is_blank(c: char) -> bool {
+    c.is_whitespace() && !matches!(c, '\n'..='\r' | '\u{85}' | '\u{2028}' | '\u{2029}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_class_ranges() {
+        let alpha = named_class_ranges(&NamedClass::Alpha).unwrap();
+        assert_eq!(alpha[..2], [('A', 'Z'), ('a', 'z')]);
+        assert!(alpha.iter().any(|&(start, end)| start <= 'ñ' && 'ñ' <= end));
+        let blank = named_class_ranges(&NamedClass::Blank).unwrap();
+        assert_eq!(blank[..2], [('\t', '\t'), (' ', ' ')]);
+        assert!(named_class_ranges(&NamedClass::Punct).is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn agrep() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_agrep"))
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("agrep-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn test_large_file() {
+    let path = temp_path("large.txt");
+    let mut file = fs::File::create(&path).unwrap();
+    let mut expected = String::new();
+    for i in 0..200_000 {
+        if i % 10_000 == 0 {
+            let line = format!("line {} has a needle in it", i);
+            writeln!(file, "{}", line).unwrap();
+            expected.push_str(&line);
+            expected.push('\n');
+        } else {
+            writeln!(file, "line {} is just hay", i).unwrap();
+        }
+    }
+    drop(file);
+
+    let output = agrep().arg("needle").arg(&path).output().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+// A matching line must be written before the rest of the input has been read, which only holds
+// if the input is streamed rather than loaded up front.
+#[test]
+fn test_streams_stdin() {
+    let mut child = agrep()
+        .arg("needle")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            tx.send(line.unwrap()).unwrap();
+        }
+    });
+
+    writeln!(stdin, "hay\nfirst needle").unwrap();
+    stdin.flush().unwrap();
+    let line = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(line, "first needle");
+
+    writeln!(stdin, "more hay\nsecond needle").unwrap();
+    drop(stdin);
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(10)).unwrap(),
+        "second needle"
+    );
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_no_match_exit_code() {
+    let output = agrep().arg("needle").stdin(Stdio::null()).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
+
+fn agrep_stdin(args: &[&str], input: &str) -> std::process::Output {
+    let mut child = agrep()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The child may exit before reading its input, e.g. on a bad pattern.
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_color_always() {
+    let output = agrep_stdin(&["--color=always", "b[0-9]"], "a b1 c b2\nnone\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a \x1b[1;31mb1\x1b[0m c \x1b[1;31mb2\x1b[0m\n"
+    );
+}
+
+#[test]
+fn test_color_never() {
+    let output = agrep_stdin(&["--color=never", "b"], "abc\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "abc\n");
+}
+
+#[test]
+fn test_only_matching() {
+    let output = agrep_stdin(&["-o", "b[0-9]"], "a b1 c b2\nnone\nb3\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "b1\nb2\nb3\n");
+}
+
+#[test]
+fn test_only_matching_line_number() {
+    let path = temp_path("versions.txt");
+    fs::write(
+        &path,
+        "agrep 1.2 and clap 4.0\nno versions here\n\nrust 1.75\n",
+    )
+    .unwrap();
+    let output = agrep()
+        .args(["-o", "-n", "[0-9]+\\.[0-9]+"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1:1.2\n1:4.0\n4:1.75\n"
+    );
+
+    let output = agrep_stdin(&["-n", "b"], "a\nb\nab\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2:b\n3:ab\n");
+}
+
+#[test]
+fn test_multiple_patterns() {
+    let output = agrep_stdin(&["-e", "fo+", "-e", "ba[rz]"], "foo\nqux\nbaz\nbar\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "foo\nbaz\nbar\n");
+
+    let path = temp_path("patterns.txt");
+    fs::write(&path, "one\ntwo\n").unwrap();
+    let output = agrep().args(["-e", "two"]).arg(&path).output().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "two\n");
+}
+
+#[test]
+fn test_word_regexp() {
+    let output = agrep_stdin(&["-w", "foo"], "a foo b\nfoobar\nfoo\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a foo b\nfoo\n");
+    let output = agrep_stdin(&["-w", "cat"], "a cat\ncategory\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a cat\n");
+}
+
+#[test]
+fn test_line_regexp() {
+    let input = "foo\nfoobar\nxfoo\nfoo\n";
+    let output = agrep_stdin(&["-x", "foo"], input);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "foo\nfoo\n");
+    let output = agrep_stdin(&["-x", "-v", "foo"], input);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "foobar\nxfoo\n");
+    let output = agrep_stdin(&["-x", "-c", "foo"], input);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2\n");
+    let output = agrep_stdin(&["-x", "-v", "-c", "fo+"], "foo\nfo\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "0\n");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_syntax() {
+    let output = agrep_stdin(&["-E", "a|"], "abc\n");
+    assert_eq!(output.status.code(), Some(2));
+    let output = agrep_stdin(&["-P", "x|"], "abc\n");
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "abc\n");
+}
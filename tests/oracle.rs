@@ -0,0 +1,227 @@
+// Differential tests: random patterns from the core subset (literals, classes, '.', alternation,
+// '*', '+', '?', '^' and '$') are rendered as pattern text and matched by the engine and by the
+// `regex` crate, which must agree on every input. A naive backtracking matcher over the same
+// pattern tree is checked as well, simple enough that a disagreement is easy to reason about.
+
+const ALPHABET: [char; 3] = ['a', 'b', 'c'];
+const CASES: usize = 3000;
+
+#[derive(Debug)]
+enum Node {
+    Literal(char),
+    Class(bool, Vec<char>),
+    Dot,
+    Empty,
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Star(Box<Node>),
+    Plus(Box<Node>),
+    Optional(Box<Node>),
+}
+
+// xorshift64, seeded so failures reproduce.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn char(&mut self) -> char {
+        ALPHABET[self.below(ALPHABET.len())]
+    }
+}
+
+fn generate(rng: &mut Rng, depth: usize) -> Node {
+    let choice = if depth == 0 {
+        rng.below(5)
+    } else {
+        rng.below(10)
+    };
+    match choice {
+        0 | 1 => Node::Literal(rng.char()),
+        2 => {
+            let chars = (0..1 + rng.below(2)).map(|_| rng.char()).collect();
+            Node::Class(rng.below(3) == 0, chars)
+        }
+        3 => Node::Dot,
+        4 if rng.below(2) == 0 => Node::Start,
+        4 => Node::End,
+        5 | 6 => Node::Concat(
+            (0..2 + rng.below(2))
+                .map(|_| generate(rng, depth - 1))
+                .collect(),
+        ),
+        // Some branches are empty, as in '(?:a|)'.
+        7 => Node::Alternation(
+            (0..2 + rng.below(2))
+                .map(|_| match rng.below(5) {
+                    0 => Node::Empty,
+                    _ => generate(rng, depth - 1),
+                })
+                .collect(),
+        ),
+        // Anchors aren't quantified, which the parser rejects.
+        _ => {
+            let inner = Box::new(match generate(rng, depth - 1) {
+                Node::Start | Node::End => Node::Literal(rng.char()),
+                node => node,
+            });
+            match rng.below(3) {
+                0 => Node::Star(inner),
+                1 => Node::Plus(inner),
+                _ => Node::Optional(inner),
+            }
+        }
+    }
+}
+
+impl Node {
+    fn render(&self) -> String {
+        match self {
+            Node::Literal(c) => c.to_string(),
+            Node::Class(negated, chars) => {
+                let chars: String = chars.iter().collect();
+                format!("[{}{}]", if *negated { "^" } else { "" }, chars)
+            }
+            Node::Dot => ".".into(),
+            Node::Empty => String::new(),
+            Node::Start => "^".into(),
+            Node::End => "$".into(),
+            Node::Concat(nodes) => nodes.iter().map(Node::render).collect(),
+            Node::Alternation(nodes) => {
+                let branches: Vec<_> = nodes.iter().map(Node::render).collect();
+                format!("(?:{})", branches.join("|"))
+            }
+            Node::Star(node) => format!("(?:{})*", node.render()),
+            Node::Plus(node) => format!("(?:{})+", node.render()),
+            Node::Optional(node) => format!("(?:{})?", node.render()),
+        }
+    }
+
+    // Every position a match of this node starting at `pos` can end at.
+    fn ends(&self, input: &[char], pos: usize) -> Vec<usize> {
+        let consume = |ok: bool| {
+            if ok {
+                vec![pos + 1]
+            } else {
+                vec![]
+            }
+        };
+        let mut ends = match self {
+            Node::Literal(c) => consume(input.get(pos) == Some(c)),
+            Node::Class(negated, chars) => consume(
+                input
+                    .get(pos)
+                    .is_some_and(|c| chars.contains(c) != *negated),
+            ),
+            Node::Dot => consume(pos < input.len()),
+            Node::Empty => vec![pos],
+            Node::Start => (pos == 0).then_some(pos).into_iter().collect(),
+            Node::End => (pos == input.len()).then_some(pos).into_iter().collect(),
+            Node::Concat(nodes) => nodes.iter().fold(vec![pos], |starts, node| {
+                starts
+                    .into_iter()
+                    .flat_map(|start| node.ends(input, start))
+                    .collect()
+            }),
+            Node::Alternation(nodes) => nodes.iter().flat_map(|n| n.ends(input, pos)).collect(),
+            Node::Star(node) => node.closure(input, vec![pos]),
+            Node::Plus(node) => node.closure(input, node.ends(input, pos)),
+            Node::Optional(node) => {
+                let mut ends = node.ends(input, pos);
+                ends.push(pos);
+                ends
+            }
+        };
+        ends.sort_unstable();
+        ends.dedup();
+        ends
+    }
+
+    // `starts` and every position reachable from them by repeating this node.
+    fn closure(&self, input: &[char], starts: Vec<usize>) -> Vec<usize> {
+        let mut seen = starts.clone();
+        let mut stack = starts;
+        while let Some(pos) = stack.pop() {
+            for end in self.ends(input, pos) {
+                if !seen.contains(&end) {
+                    seen.push(end);
+                    stack.push(end);
+                }
+            }
+        }
+        seen
+    }
+
+    fn is_match(&self, input: &[char]) -> bool {
+        (0..=input.len()).any(|start| !self.ends(input, start).is_empty())
+    }
+
+    fn is_full_match(&self, input: &[char]) -> bool {
+        self.ends(input, 0).contains(&input.len())
+    }
+}
+
+fn inputs(rng: &mut Rng) -> Vec<String> {
+    let mut inputs = vec![String::new()];
+    for _ in 0..8 {
+        let len = rng.below(6);
+        inputs.push((0..len).map(|_| rng.char()).collect());
+    }
+    inputs
+}
+
+#[test]
+fn test_agrees_with_oracle() {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..CASES {
+        let node = generate(&mut rng, 3);
+        let pattern = node.render();
+        let nfa = agrep::compile(&pattern).unwrap_or_else(|err| panic!("{}: {}", pattern, err));
+        let reference = regex::Regex::new(&pattern).unwrap();
+        let full_reference = regex::Regex::new(&format!("\\A(?:{})\\z", pattern)).unwrap();
+        for input in inputs(&mut rng) {
+            let chars: Vec<char> = input.chars().collect();
+            let is_match = nfa.is_match(&input);
+            assert_eq!(
+                is_match,
+                reference.is_match(&input),
+                "is_match({:?}, {:?}) against regex",
+                pattern,
+                input
+            );
+            assert_eq!(
+                is_match,
+                node.is_match(&chars),
+                "is_match({:?}, {:?}) against the naive matcher",
+                pattern,
+                input
+            );
+            let is_full_match = nfa.is_full_match(&input);
+            assert_eq!(
+                is_full_match,
+                full_reference.is_match(&input),
+                "is_full_match({:?}, {:?}) against regex",
+                pattern,
+                input
+            );
+            assert_eq!(
+                is_full_match,
+                node.is_full_match(&chars),
+                "is_full_match({:?}, {:?}) against the naive matcher",
+                pattern,
+                input
+            );
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Behavioural contract for the engine: a matrix of patterns matched against inputs they must
+// and must not match.
+
+use agrep::nfa::NFA;
+use agrep::regex::Regex;
+
+fn compile(pattern: &str) -> NFA {
+    agrep::compile(pattern).unwrap_or_else(|err| panic!("{}: {}", pattern, err))
+}
+
+// Asserts `pattern` matches all of `accepts` in full and none of `rejects` anywhere.
+fn check(pattern: &str, accepts: &[&str], rejects: &[&str]) {
+    let nfa = compile(pattern);
+    for input in accepts {
+        assert!(
+            nfa.is_full_match(input),
+            "{} should match {:?}",
+            pattern,
+            input
+        );
+    }
+    for input in rejects {
+        assert!(
+            !nfa.is_match(input),
+            "{} shouldn't match {:?}",
+            pattern,
+            input
+        );
+    }
+}
+
+fn find(pattern: &str, input: &str) -> Option<(usize, usize)> {
+    compile(pattern).find(input).map(|m| (m.start(), m.end()))
+}
+
+#[test]
+fn test_literals() {
+    check("a", &["a"], &["", "b", "A"]);
+    check("abc", &["abc"], &["ab", "acb", "bc"]);
+    check("é∂", &["é∂"], &["e∂", "é"]);
+    assert_eq!(find("bc", "abcbc"), Some((1, 3)));
+    assert_eq!(find("\\.", "a.b"), Some((1, 2)));
+    check("\\x41", &["A"], &["a", "x41"]);
+    check("\\x{263A}", &["☺"], &["x"]);
+    check("\\cI", &["\t"], &["I", "cI"]);
+    check("\\cA\\cz", &["\x01\x1A"], &["\x01"]);
+}
+
+#[test]
+fn test_classes() {
+    check("[abc]", &["a", "b", "c"], &["d", ""]);
+    check("[a-c]", &["a", "b", "c"], &["d", "`"]);
+    check("[a-cx-z0]", &["b", "y", "0"], &["d", "w", "1"]);
+    check("[a-]", &["a", "-"], &["b"]);
+    check("[]a]", &["]", "a"], &["b"]);
+    check("[a[b]", &["a", "[", "b"], &["c", "]"]);
+    check("\\d+", &["0", "42"], &["x", ""]);
+    check("[\\w.]", &["_", "."], &["-", " "]);
+    check("[[:digit:]x]", &["7", "x"], &["y"]);
+    check("[\\x41-\\x43]", &["A", "B", "C"], &["-", "D", "x"]);
+    check("[\\x{263A}-\\x{263C}]", &["☺", "☻", "☼"], &["☹", "x"]);
+    check("[!-\\x7E]", &["!", "a", "~"], &[" ", "\x7F"]);
+    check(".", &["a", "∂"], &["", "\n"]);
+    check("(?s).", &["a", "\n"], &[""]);
+    // '\b' is backspace in a class but a word boundary outside one.
+    check("[\\b]", &["\u{8}"], &["b", "\\", ""]);
+    check("a[\\bc]", &["a\u{8}", "ac"], &["ab", "a"]);
+    assert_eq!(find("a\\b", "ab a"), Some((3, 4)));
+    assert_eq!(find("a\\b", "a\u{8}"), Some((0, 1)));
+}
+
+#[test]
+fn test_negated_classes() {
+    check("[^a]", &["b", "\n"], &["a", ""]);
+    check("[^a-c]", &["d", "0"], &["a", "b", "c"]);
+    check("x[^0-9]", &["xa"], &["x1", "x"]);
+}
+
+#[test]
+fn test_anchors() {
+    check("^ab", &["ab"], &["xab", "a"]);
+    check("ab$", &["ab"], &["abx", "b"]);
+    check("^$", &[""], &["a"]);
+    assert_eq!(find("^a", "aa"), Some((0, 1)));
+    assert_eq!(find("a$", "aa"), Some((1, 2)));
+    assert_eq!(find("\\bcat\\b", "a cat"), Some((2, 5)));
+    assert_eq!(find("\\bcat\\b", "category"), None);
+    assert_eq!(find("foo\\Kbar", "foobar"), Some((3, 6)));
+}
+
+// Without multiline mode, a buffer of several lines has one start and one end, and by default
+// '.' stays within a line.
+#[test]
+fn test_multi_line_buffer() {
+    assert_eq!(find("^ab", "ab\ncd"), Some((0, 2)));
+    assert_eq!(find("^cd", "ab\ncd"), None);
+    assert_eq!(find("cd$", "ab\ncd"), Some((3, 5)));
+    assert_eq!(find("ab$", "ab\ncd"), None);
+    assert_eq!(find("b.c", "ab\ncd"), None);
+    assert_eq!(find(".+", "ab\ncd"), Some((0, 2)));
+    assert_eq!(find("(?s)b.c", "ab\ncd"), Some((1, 4)));
+}
+
+#[test]
+fn test_repetitions() {
+    check("a*", &["", "a", "aaaa"], &[]);
+    check("ba+", &["ba", "baaa"], &["b", "ab"]);
+    check("ab?c", &["ac", "abc"], &["abbc"]);
+    check("a{3}", &["aaa"], &["aa"]);
+    check("xa{2,}", &["xaa", "xaaaa"], &["xa"]);
+    check("xa{1,2}y", &["xay", "xaay"], &["xy", "xaaay"]);
+    check("(ab)+", &["ab", "abab"], &["ba", "a"]);
+    assert_eq!(find("a+", "baaab"), Some((1, 4)));
+    assert_eq!(find("a{1,2}", "aaa"), Some((0, 2)));
+    assert_eq!(find("a+?", "baaa"), Some((1, 2)));
+    assert_eq!(find("a??b", "ab"), Some((0, 2)));
+}
+
+#[test]
+fn test_alternations() {
+    check("cat|dog", &["cat", "dog"], &["cow", "ca"]);
+    check("a|b|[c-e]", &["a", "b", "d"], &["f"]);
+    check("x(a|bc)y", &["xay", "xbcy"], &["xy", "xaby"]);
+    // Leftmost-first: the earlier branch wins even when a later one is longer.
+    assert_eq!(find("a|ab", "ab"), Some((0, 1)));
+    assert_eq!(find("ab|a", "ab"), Some((0, 2)));
+}
+
+#[test]
+fn test_groups() {
+    let nfa = compile("(a+)(b*)");
+    let caps = nfa.captures("xaabbb").unwrap();
+    assert_eq!(caps.text(1), Some("aa"));
+    assert_eq!(caps.text(2), Some("bbb"));
+    check("((a)b)c", &["abc"], &["ab", "bc"]);
+    check("(?:ab)+", &["abab"], &["ba", "a"]);
+    check("a(?#the letter a)b", &["ab"], &["a", "the letter a"]);
+}
+
+#[test]
+fn test_epsilon() {
+    check("", &[""], &[]);
+    check("()", &[""], &[]);
+    check("(a|)b", &["ab", "b"], &["a"]);
+    for pattern in ["(|a|)", "(a|)", "(|a)"] {
+        check(pattern, &["", "a"], &[]);
+        assert!(!compile(pattern).is_full_match("aa"), "{}", pattern);
+    }
+    check("(a*)*b", &["b", "aab"], &["a"]);
+    assert_eq!(find("x*", "abc"), Some((0, 0)));
+    assert_eq!(compile("a*").find_iter("baa").count(), 2);
+}
+
+#[test]
+fn test_multi_char_literal() {
+    let regex = Regex::Literal(vec!['a', 'b'].into_boxed_slice());
+    let nfa = NFA::from_regex(&regex);
+    assert!(nfa.is_full_match("ab"));
+    assert!(!nfa.is_match("ax"));
+    assert!(!nfa.is_full_match("a"));
+}
@@ -0,0 +1,34 @@
+use std::fs;
+use std::io::{BufReader, Write};
+
+#[test]
+fn test_replace_all_stream() {
+    let path = std::env::temp_dir().join(format!("agrep-{}-replace.txt", std::process::id()));
+    let mut file = fs::File::create(&path).unwrap();
+    write!(
+        file,
+        "version 1.2\nnothing\n\nversion 10.0 and version 3.4\nlast 5.6"
+    )
+    .unwrap();
+    drop(file);
+
+    let nfa = agrep::compile("(\\d+)\\.(\\d+)").unwrap();
+    let mut out = vec![];
+    let reader = BufReader::new(fs::File::open(&path).unwrap());
+    nfa.replace_all_stream(reader, &mut out, "$2.$1").unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "version 2.1\nnothing\n\nversion 0.10 and version 4.3\nlast 6.5"
+    );
+}
+
+#[test]
+fn test_replace_all_stream_per_line() {
+    // '$' matches at the end of each line, since lines are rewritten one at a time.
+    let nfa = agrep::compile("$").unwrap();
+    let mut out = vec![];
+    nfa.replace_all_stream("a\nb\n".as_bytes(), &mut out, ";")
+        .unwrap();
+    assert_eq!(out, b"a;\nb;\n");
+}